@@ -0,0 +1,79 @@
+//! What can go wrong while downloading.
+
+use std::io;
+
+use rand::distributions::WeightedError;
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::is_tls_version_mismatch;
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    /// The request failed without a response, or the body broke off.
+    #[error("{0}")]
+    Http(reqwest::Error),
+    /// The server answered, but not with the content asked for.
+    #[error("status code {}", .0.as_str())]
+    Status(StatusCode),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Timeout(reqwest::Error),
+    /// A Content-Length that isn't a number.
+    #[error("Failed to parse Content-Length: {0}")]
+    ContentLength(String),
+    /// Fewer bytes than --min-bytes.
+    #[error("got {got} bytes, expected at least {min}")]
+    TooSmall { got: u64, min: u64 },
+    #[error("expected sha256 {expected}, got {got}")]
+    Checksum { expected: String, got: String },
+    /// A range of a --split download came back wrong.
+    #[error("{0}")]
+    Range(String),
+    /// A partial file that doesn't continue the server's current copy.
+    #[error("Cannot resume {0}")]
+    Resume(String),
+    #[error("Disk full while writing {0}")]
+    DiskFull(String),
+    #[error("TLS version negotiation failed, check --min-tls/--max-tls: {0}")]
+    TlsVersion(reqwest::Error),
+    #[error("redirect chain cut off, check --max-redirects: {0}")]
+    Redirect(reqwest::Error),
+    #[error("{0}")]
+    Tls(#[from] rustls::Error),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+    /// Weighted URLs with no positive weight to pick by.
+    #[error("invalid URL weights: {0}")]
+    Weights(#[from] WeightedError),
+}
+
+impl DownloadError {
+    /// Coarse classification of a request that got no response at all.
+    pub fn category(&self) -> &'static str {
+        match self {
+            DownloadError::Timeout(_) => "timeout",
+            DownloadError::Redirect(_) => "redirect",
+            DownloadError::TlsVersion(_) => "connect",
+            DownloadError::Http(e) if e.is_connect() => "connect",
+            _ => "request",
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            DownloadError::Timeout(e)
+        } else if is_tls_version_mismatch(&e) {
+            DownloadError::TlsVersion(e)
+        } else if e.is_redirect() {
+            DownloadError::Redirect(e)
+        } else {
+            DownloadError::Http(e)
+        }
+    }
+}
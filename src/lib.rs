@@ -0,0 +1,3387 @@
+//! The downloader behind the `auto-fast-dl` binary: build a [`Downloader`]
+//! from [`DownloadOptions`] and call [`Downloader::start`] from one or more
+//! tasks.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use colored::Colorize;
+use futures::{future::Either, StreamExt};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use num_format::{Locale, ToFormattedString};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng,
+};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sysinfo::{Disks, Pid, ProcessesToUpdate, System};
+use tokio::{
+    fs::File,
+    io::BufWriter,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{watch, Mutex, Semaphore, SemaphorePermit},
+};
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument};
+use uuid::Uuid;
+
+/// `println!`, unless --quiet asked for nothing but errors and the final stats.
+macro_rules! say {
+    ($downloader:expr, $($arg:tt)*) => {
+        if !$downloader.options.quiet {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub mod error;
+pub mod sink;
+
+use error::DownloadError;
+use sink::{Body, Sink, SinkKind};
+
+pub const VERSION: &str = "3.1.0r";
+pub const DEFAULT_DOWNLOAD_DIR: &str = "downloads";
+pub const DEFAULT_MAX_MEMORY_MB: u64 = 300;
+pub const DEFAULT_BATCH_INTERVAL_MS: u64 = 1000;
+/// Narrowest terminal the full-width ASCII banners render correctly in.
+const WIDE_BANNER_COLUMNS: u16 = 70;
+/// Upper bound for a single retry backoff.
+const MAX_RETRY_DELAY_MS: u64 = 30_000;
+/// 429 responses are retried at least this many times, even with --retries 0.
+const MIN_RATE_LIMITED_RETRIES: u32 = 3;
+/// Weight of the newest batch in the smoothed download rate.
+const RATE_SMOOTHING: f64 = 0.3;
+/// Appended to a file name for the --resume sidecar describing it.
+const RESUME_SUFFIX: &str = ".resume";
+
+tokio::task_local! {
+    /// Files created by the batch running on this task. Each session cleans
+    /// up only its own batch, so files another thread is still writing stay.
+    static BATCH_FILES: std::cell::RefCell<Vec<String>>;
+}
+
+/// Note `path` as created by the current batch. Does nothing outside of
+/// `Downloader::start`, where no batch owns the file.
+fn track_batch_file(path: &str) {
+    let _ = BATCH_FILES.try_with(|files| files.borrow_mut().push(path.to_string()));
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// Leave flushing to the operating system
+    #[default]
+    None,
+    /// fsync every file right after it is written
+    PerFile,
+    /// fsync all files written by a batch once the batch completes
+    Batch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WriteErrorPolicy {
+    /// Count the download as failed
+    #[default]
+    Fail,
+    /// Drop the content but count the download as successful
+    Discard,
+    /// Retry the write once under a generated file name
+    SafeName,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressUnit {
+    /// Count finished files
+    #[default]
+    Files,
+    /// Count body bytes as they arrive, out of the Content-Lengths seen so far
+    Bytes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn to_reqwest(self) -> reqwest::tls::Version {
+        match self {
+            TlsVersion::Tls12 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls13 => reqwest::tls::Version::TLS_1_3,
+        }
+    }
+
+    fn to_rustls(self) -> &'static rustls::SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// Keep credentials out of `--print-config` output.
+fn mask_secret<T, S: serde::Serializer>(
+    secret: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_str("***"),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Header names with their values masked, since any of them may carry a
+/// token or API key.
+fn mask_header_values<S: serde::Serializer>(
+    headers: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(headers.iter().map(|(name, _)| (name, "***")))
+}
+
+/// `url` with any password replaced, for display.
+pub fn redact_url(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("***"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn serialize_redacted_url<S: serde::Serializer>(
+    url: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match url {
+        Some(url) => serializer.serialize_str(&redact_url(url)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Counters bumped by every download, kept out of the stats mutex so
+/// concurrent downloads don't serialize on it.
+#[derive(Debug, Default)]
+pub struct Counters {
+    total_files: AtomicUsize,
+    failed_downloads: AtomicUsize,
+    total_bytes: AtomicU64,
+    /// Body bytes received, including those of downloads that failed later.
+    wire_bytes: AtomicU64,
+    /// Highest process memory reading seen after a download finished, as the
+    /// bits of an `f64` in MB. Non-negative floats order like their bits, and
+    /// 0 means no reading yet.
+    peak_memory_mb: AtomicU64,
+    /// Responses with each of `COMMON_STATUSES`, in the same order.
+    common_statuses: [AtomicUsize; COMMON_STATUSES.len()],
+    /// Responses with any other status code, rare enough to share a lock.
+    other_statuses: std::sync::Mutex<HashMap<u16, usize>>,
+}
+
+/// Status codes counted without taking a lock.
+const COMMON_STATUSES: [u16; 15] = [
+    200, 204, 206, 301, 302, 304, 400, 401, 403, 404, 429, 500, 502, 503, 504,
+];
+
+impl Counters {
+    pub fn total_files(&self) -> usize {
+        self.total_files.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_downloads(&self) -> usize {
+        self.failed_downloads.load(Ordering::Relaxed)
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::Relaxed)
+    }
+
+    fn add_files(&self, n: usize) {
+        self.total_files.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn add_failure(&self) {
+        self.failed_downloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_bytes(&self, n: u64) {
+        self.total_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn wire_bytes(&self) -> u64 {
+        self.wire_bytes.load(Ordering::Relaxed)
+    }
+
+    fn add_wire_bytes(&self, n: u64) {
+        self.wire_bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn peak_memory_mb(&self) -> Option<f64> {
+        match self.peak_memory_mb.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    fn record_memory_mb(&self, mb: f64) {
+        self.peak_memory_mb
+            .fetch_max(mb.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Responses seen so far by status code.
+    pub fn status_counts(&self) -> HashMap<u16, usize> {
+        let mut counts = self.other_statuses.lock().unwrap().clone();
+        for (status, count) in COMMON_STATUSES.iter().zip(&self.common_statuses) {
+            match count.load(Ordering::Relaxed) {
+                0 => {}
+                count => {
+                    counts.insert(*status, count);
+                }
+            }
+        }
+        counts
+    }
+
+    fn add_status(&self, status: u16) {
+        match COMMON_STATUSES.iter().position(|common| *common == status) {
+            Some(i) => {
+                self.common_statuses[i].fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                *self
+                    .other_statuses
+                    .lock()
+                    .unwrap()
+                    .entry(status)
+                    .or_default() += 1
+            }
+        }
+    }
+}
+
+/// Validators from the last complete response for a URL, kept in
+/// --cache-manifest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl CacheEntry {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| Some(headers.get(name)?.to_str().ok()?.to_string());
+        CacheEntry {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+}
+
+/// Where a partial file came from, kept next to it in `<file>.resume` so
+/// --resume only continues it from the same URL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResumeInfo {
+    url: String,
+    /// The complete length, when the response that started the file had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    pub start_time: Option<u64>,
+    pub slowest: Vec<FileTiming>,
+    pub fastest: Vec<FileTiming>,
+    pub protocols: HashMap<String, usize>,
+    pub disk_wait: Duration,
+    pub batch_rates: Vec<f64>,
+    pub ttfb_samples: Vec<Duration>,
+    pub stale_connection_retries: usize,
+    pub undersized_files: usize,
+    pub write_errors: usize,
+    pub skipped_unchanged: usize,
+    pub skipped_existing: usize,
+    pub not_modified: usize,
+    /// Downloads that failed since the last one that didn't.
+    pub consecutive_failures: usize,
+    pub error_counts: HashMap<&'static str, usize>,
+    pub rate_limit_pauses: usize,
+    pub rate_limit_wait: Duration,
+    pub content_hashes: HashSet<String>,
+    pub divergent_batches: usize,
+    pub resumed_downloads: usize,
+    pub retries: usize,
+    pub truncated_redirects: usize,
+    pub checksum_mismatches: usize,
+    /// Per-URL outcomes, only kept when requests are spread over several URLs.
+    pub url_counts: HashMap<String, UrlCounts>,
+    /// Why the session ended on its own, `None` when it was interrupted.
+    pub stop_reason: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct UrlCounts {
+    pub downloaded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightedUrl {
+    pub url: String,
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTiming {
+    pub url: String,
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl DownloadStats {
+    /// Keep `timing` if it ranks among the `top_n` slowest or fastest downloads.
+    fn record_timing(&mut self, timing: FileTiming, top_n: usize) {
+        let pos = self
+            .slowest
+            .partition_point(|t| t.duration >= timing.duration);
+        if pos < top_n {
+            self.slowest.insert(pos, timing.clone());
+            self.slowest.truncate(top_n);
+        }
+        let pos = self
+            .fastest
+            .partition_point(|t| t.duration <= timing.duration);
+        if pos < top_n {
+            self.fastest.insert(pos, timing);
+            self.fastest.truncate(top_n);
+        }
+    }
+}
+
+/// Token bucket shared by every download for `--rate-limit`. Tokens are bytes
+/// and may go negative; a reader waits until the debt is paid back.
+#[derive(Debug)]
+struct Bandwidth {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Request pacing derived from the server's rate-limit headers.
+#[derive(Debug, Default)]
+struct RateLimitState {
+    /// Earliest time the next request may be sent.
+    next_allowed: Option<Instant>,
+    /// Gap between requests that spreads the remaining budget until reset.
+    spacing: Duration,
+}
+
+/// What `download_file` did with a URL that didn't fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadOutcome {
+    /// Body bytes kept, zero when the body wasn't read.
+    Downloaded(u64),
+    Skipped,
+}
+
+/// Optional behaviour toggled from the command line.
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadOptions {
+    pub top_n: Option<usize>,
+    pub alpn: Option<Vec<String>>,
+    pub max_concurrent_writes: Option<u32>,
+    pub rate_histogram: bool,
+    pub measure_only_first_byte: bool,
+    pub min_bytes: u64,
+    #[serde(serialize_with = "mask_secret")]
+    pub webhook: Option<String>,
+    pub progress_delay_ms: u64,
+    pub progress: ProgressUnit,
+    pub quiet: bool,
+    pub fsync: FsyncPolicy,
+    pub run_name: String,
+    pub sysinfo_refresh_ms: u64,
+    pub on_write_error: WriteErrorPolicy,
+    pub accept: Option<String>,
+    pub accept_language: Option<String>,
+    #[serde(serialize_with = "mask_header_values")]
+    pub headers: Vec<(String, String)>,
+    pub modified_since: Option<DateTime<Utc>>,
+    pub min_tls: Option<TlsVersion>,
+    pub max_tls: Option<TlsVersion>,
+    pub fallback_batch_size: u64,
+    pub only_status: bool,
+    pub respect_rate_limit_headers: bool,
+    pub rate_limit_remaining_header: String,
+    pub rate_limit_reset_header: String,
+    pub ramp_up_secs: u64,
+    pub verify_identical: bool,
+    pub report_every_secs: Option<u64>,
+    pub min_rate: Option<f64>,
+    pub min_rate_window_secs: u64,
+    pub weighted_urls: Vec<WeightedUrl>,
+    pub url_list: Vec<String>,
+    pub split: Option<u64>,
+    pub resume: bool,
+    pub retries: u32,
+    pub retry_base_ms: u64,
+    pub max_concurrency: Option<u32>,
+    pub bandwidth_limit: Option<u64>,
+    #[serde(serialize_with = "mask_secret")]
+    pub basic_auth: Option<(String, String)>,
+    #[serde(serialize_with = "mask_secret")]
+    pub bearer_token: Option<String>,
+    #[serde(serialize_with = "serialize_redacted_url")]
+    pub proxy: Option<String>,
+    pub user_agent: Option<String>,
+    pub max_redirects: Option<usize>,
+    pub sha256: Option<String>,
+    pub checksums: HashMap<String, String>,
+    pub use_server_filename: bool,
+    pub default_ext: Option<String>,
+    pub json_output: Option<PathBuf>,
+    pub csv_log: Option<PathBuf>,
+    pub failures_file: Option<PathBuf>,
+    pub batch_interval_ms: Option<u64>,
+    pub batches: Option<u64>,
+    pub duration_secs: Option<u64>,
+    pub fail_threshold: f64,
+    pub max_consecutive_failures: Option<u64>,
+    pub force: bool,
+    pub keep: bool,
+    pub min_free_disk_mb: Option<u64>,
+    pub cache_manifest: Option<PathBuf>,
+    pub sink: SinkKind,
+}
+
+/// The same defaults as the command line flags.
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            top_n: Default::default(),
+            alpn: Default::default(),
+            max_concurrent_writes: Default::default(),
+            rate_histogram: Default::default(),
+            measure_only_first_byte: Default::default(),
+            min_bytes: Default::default(),
+            webhook: Default::default(),
+            progress_delay_ms: 200,
+            progress: Default::default(),
+            quiet: Default::default(),
+            fsync: Default::default(),
+            run_name: Default::default(),
+            sysinfo_refresh_ms: 1000,
+            on_write_error: Default::default(),
+            accept: Default::default(),
+            accept_language: Default::default(),
+            headers: Default::default(),
+            modified_since: Default::default(),
+            min_tls: Default::default(),
+            max_tls: Default::default(),
+            fallback_batch_size: 5,
+            only_status: Default::default(),
+            respect_rate_limit_headers: Default::default(),
+            rate_limit_remaining_header: "X-RateLimit-Remaining".to_string(),
+            rate_limit_reset_header: "X-RateLimit-Reset".to_string(),
+            ramp_up_secs: Default::default(),
+            verify_identical: Default::default(),
+            report_every_secs: Default::default(),
+            min_rate: Default::default(),
+            min_rate_window_secs: 30,
+            weighted_urls: Default::default(),
+            url_list: Default::default(),
+            split: Default::default(),
+            resume: Default::default(),
+            retries: Default::default(),
+            retry_base_ms: 500,
+            max_concurrency: Default::default(),
+            bandwidth_limit: Default::default(),
+            basic_auth: Default::default(),
+            bearer_token: Default::default(),
+            proxy: Default::default(),
+            user_agent: Default::default(),
+            max_redirects: Default::default(),
+            sha256: Default::default(),
+            checksums: Default::default(),
+            use_server_filename: Default::default(),
+            default_ext: Default::default(),
+            json_output: Default::default(),
+            csv_log: Default::default(),
+            failures_file: Default::default(),
+            batch_interval_ms: Default::default(),
+            batches: Default::default(),
+            duration_secs: Default::default(),
+            fail_threshold: Default::default(),
+            max_consecutive_failures: Default::default(),
+            force: Default::default(),
+            keep: Default::default(),
+            min_free_disk_mb: Default::default(),
+            cache_manifest: Default::default(),
+            sink: Default::default(),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// The digest downloads of `url` have to match, if one was given.
+    fn expected_sha256(&self, url: &str) -> Option<&str> {
+        self.checksums
+            .get(url)
+            .or(self.sha256.as_ref())
+            .map(String::as_str)
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.user_agent
+            .clone()
+            .unwrap_or_else(|| format!("auto-fast-dl/{VERSION}"))
+    }
+
+    /// Headers sent with every request.
+    fn default_headers(&self) -> Result<HeaderMap, DownloadError> {
+        let value = |value: &str| {
+            HeaderValue::from_str(value).map_err(|_| {
+                DownloadError::InvalidHeader(format!("`{value}` is not a valid value"))
+            })
+        };
+        let mut headers = HeaderMap::new();
+        let negotiation = [
+            (header::ACCEPT, &self.accept),
+            (header::ACCEPT_LANGUAGE, &self.accept_language),
+        ];
+        for (name, value_str) in negotiation {
+            if let Some(value_str) = value_str {
+                headers.insert(name, value(value_str)?);
+            }
+        }
+        for (name, value_str) in &self.headers {
+            let name = header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                DownloadError::InvalidHeader(format!("`{name}` is not a valid name"))
+            })?;
+            headers.insert(name, value(value_str)?);
+        }
+        Ok(headers)
+    }
+
+    /// The --proxy for every request, skipping the hosts in `NO_PROXY`.
+    fn proxy(&self) -> Result<Option<reqwest::Proxy>, DownloadError> {
+        let Some(proxy) = &self.proxy else {
+            return Ok(None);
+        };
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| DownloadError::InvalidUrl(format!("{proxy} as a proxy: {e}")))?;
+        Ok(Some(proxy.no_proxy(reqwest::NoProxy::from_env())))
+    }
+}
+
+/// Bumped whenever a `StatsReport` field is renamed or removed.
+const STATS_SCHEMA_VERSION: u32 = 1;
+
+/// Machine-readable summary of a download session.
+#[derive(Debug, Serialize)]
+pub struct StatsReport {
+    pub schema_version: u32,
+    pub version: &'static str,
+    pub run_name: String,
+    pub outcome: &'static str,
+    /// Why the session ended on its own, `None` when it was stopped.
+    pub stop_reason: Option<String>,
+    pub started_at: Option<u64>,
+    pub finished_at: u64,
+    pub elapsed_secs: u64,
+    pub total_files: usize,
+    pub failed_downloads: usize,
+    pub total_bytes: u64,
+    pub files_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub peak_memory_mb: Option<f64>,
+}
+
+pub struct Downloader {
+    download_dir: String,
+    max_memory_mb: AtomicU64,
+    pub stats: Arc<Mutex<DownloadStats>>,
+    pub counters: Arc<Counters>,
+    write_permits: Option<Semaphore>,
+    request_permits: Option<Semaphore>,
+    unsynced_files: Mutex<Vec<String>>,
+    portal_warned: AtomicBool,
+    memory_warned: AtomicBool,
+    aborted: AtomicBool,
+    /// Set by [`Downloader::stop`], watched by every session.
+    stop: watch::Sender<bool>,
+    /// Batches started by all sessions, checked against --batches and used
+    /// to number them in --failures-file.
+    batches_started: AtomicU64,
+    system: Arc<RwLock<System>>,
+    background_tasks_started: AtomicBool,
+    rate_limit: Mutex<RateLimitState>,
+    bandwidth: Mutex<Bandwidth>,
+    /// (url, file, hash) of every file downloaded by the running batch.
+    batch_hashes: Mutex<Vec<(String, String, String)>>,
+    /// Files left by an interrupted run, reused by the next batches that
+    /// download their URL.
+    resumable: Mutex<Vec<(String, ResumeInfo)>>,
+    /// Server-provided names handed out since the last cleanup.
+    reserved_names: std::sync::Mutex<HashSet<String>>,
+    /// Held while appending to `--csv-log` so rows from different threads
+    /// don't interleave with the header.
+    csv_log: std::sync::Mutex<()>,
+    /// Validators by URL, loaded from and saved to --cache-manifest.
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    sink: Box<dyn Sink>,
+    /// Built from the options once they're validated by `new`.
+    default_headers: HeaderMap,
+    proxy: Option<reqwest::Proxy>,
+    pub options: DownloadOptions,
+}
+
+impl Downloader {
+    pub fn new(
+        download_dir: Option<String>,
+        max_memory_mb: Option<u64>,
+        options: DownloadOptions,
+    ) -> Result<Self, DownloadError> {
+        let mut this = Downloader {
+            download_dir: download_dir.unwrap_or_else(|| DEFAULT_DOWNLOAD_DIR.to_string()),
+            max_memory_mb: AtomicU64::new(max_memory_mb.unwrap_or(DEFAULT_MAX_MEMORY_MB)),
+            stats: Arc::new(Mutex::new(DownloadStats::default())),
+            counters: Arc::new(Counters::default()),
+            write_permits: options
+                .max_concurrent_writes
+                .map(|n| Semaphore::new(n as usize)),
+            request_permits: options.max_concurrency.map(|n| Semaphore::new(n as usize)),
+            unsynced_files: Mutex::new(Vec::new()),
+            portal_warned: AtomicBool::new(false),
+            memory_warned: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            stop: watch::Sender::new(false),
+            batches_started: AtomicU64::new(0),
+            system: Arc::new(RwLock::new(System::new_all())),
+            background_tasks_started: AtomicBool::new(false),
+            rate_limit: Mutex::new(RateLimitState::default()),
+            bandwidth: Mutex::new(Bandwidth {
+                tokens: options.bandwidth_limit.unwrap_or(0) as f64,
+                refilled_at: Instant::now(),
+            }),
+            batch_hashes: Mutex::new(Vec::new()),
+            resumable: Mutex::new(Vec::new()),
+            reserved_names: std::sync::Mutex::new(HashSet::new()),
+            csv_log: std::sync::Mutex::new(()),
+            cache: Mutex::new(
+                options
+                    .cache_manifest
+                    .as_deref()
+                    .map(load_cache_manifest)
+                    .unwrap_or_default(),
+            ),
+            sink: options.sink.sink(),
+            default_headers: options.default_headers()?,
+            proxy: options.proxy()?,
+            options,
+        };
+        this.setup_download_dir()?;
+        if this.options.resume {
+            let partial_files = this.partial_files();
+            if !partial_files.is_empty() {
+                say!(this, "Resuming {} partial downloads", partial_files.len());
+            }
+            *this.resumable.get_mut() = partial_files;
+        }
+        Ok(this)
+    }
+
+    /// Whether a session gave up on its own, e.g. below --min-rate.
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Make every session end: the batch in flight is abandoned and `start`
+    /// returns. Sessions started after this return right away.
+    pub fn stop(&self) {
+        self.stop.send_replace(true);
+    }
+
+    /// Write the validators collected so far to --cache-manifest, if set.
+    pub async fn save_cache_manifest(&self) {
+        let Some(path) = &self.options.cache_manifest else {
+            return;
+        };
+        // Held while writing so sessions don't write over each other
+        let cache = self.cache.lock().await;
+        let written = serde_json::to_string_pretty(&*cache)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(path, json + "\n"));
+        if let Err(e) = written {
+            error!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    /// Append `row` to the CSV log at `path`, writing the header first when
+    /// the file is new or empty.
+    fn append_csv_row(&self, path: &Path, row: &str) -> io::Result<()> {
+        let _guard = self.csv_log.lock().unwrap();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(
+                b"timestamp,run_name,batch_size,successful,failed,bytes,files_per_sec\n",
+            )?;
+        }
+        file.write_all(row.as_bytes())
+    }
+
+    fn setup_download_dir(&self) -> io::Result<()> {
+        if !Path::new(&self.download_dir).exists() {
+            fs::create_dir_all(&self.download_dir)?;
+        }
+        Ok(())
+    }
+
+    /// The non-empty files in the download directory that were named by us,
+    /// i.e. `<uuid>.<ext>`, and have a sidecar saying where they came from.
+    fn partial_files(&self) -> Vec<(String, ResumeInfo)> {
+        let Ok(files) = fs::read_dir(&self.download_dir) else {
+            return Vec::new();
+        };
+        files
+            .filter_map(|file| {
+                let file = file.ok()?;
+                let name = file.file_name().into_string().ok()?;
+                let non_empty = file.metadata().ok()?.len() > 0;
+                let generated = !name.ends_with(RESUME_SUFFIX)
+                    && name
+                        .split_once('.')
+                        .is_some_and(|(stem, _)| Uuid::parse_str(stem).is_ok());
+                if !generated || !non_empty {
+                    return None;
+                }
+                let info = self.resume_info(&name)?;
+                Some((name, info))
+            })
+            .collect()
+    }
+
+    /// The sidecar of `name`, if it has a readable one.
+    fn resume_info(&self, name: &str) -> Option<ResumeInfo> {
+        let path = format!("{}/{}{}", self.download_dir, name, RESUME_SUFFIX);
+        serde_json::from_slice(&fs::read(path).ok()?).ok()
+    }
+
+    /// Record where `name` is being downloaded from, for a later --resume.
+    fn write_resume_info(&self, name: &str, info: &ResumeInfo) {
+        let path = format!("{}/{}{}", self.download_dir, name, RESUME_SUFFIX);
+        let written = serde_json::to_vec(info)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&path, json));
+        match written {
+            Ok(()) => track_batch_file(&path),
+            Err(e) => warn!("Failed to write {path}, {name} won't be resumed: {e}"),
+        }
+    }
+
+    /// Remove a file that failed its checks, and its --resume sidecar, so
+    /// neither --keep nor --resume holds on to it.
+    fn remove_rejected(&self, name: &str) {
+        let file_path = format!("{}/{}", self.download_dir, name);
+        remove_downloaded_file(Path::new(&file_path));
+        remove_downloaded_file(Path::new(&format!("{file_path}{RESUME_SUFFIX}")));
+    }
+
+    /// A partial file left by an earlier run for `url`, if there is one.
+    async fn take_resumable(&self, url: &str) -> Option<String> {
+        let mut resumable = self.resumable.lock().await;
+        let index = resumable.iter().position(|(_, info)| info.url == url)?;
+        Some(resumable.swap_remove(index).0)
+    }
+
+    /// Claim `name` for a download, or `name-1`, `name-2`, ... when another
+    /// download in this batch or a file on disk already has it.
+    fn reserve_file_name(&self, name: &str) -> String {
+        let (stem, extension) = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+            _ => (name, String::new()),
+        };
+        let mut reserved = self.reserved_names.lock().unwrap();
+        let candidate = (0..)
+            .map(|n| match n {
+                0 => name.to_string(),
+                n => format!("{stem}-{n}{extension}"),
+            })
+            .find(|candidate| {
+                !reserved.contains(candidate)
+                    && !Path::new(&self.download_dir).join(candidate).exists()
+            })
+            .unwrap();
+        reserved.insert(candidate.clone());
+        candidate
+    }
+
+    /// Whether the download directory already holds `name` with exactly `len`
+    /// bytes.
+    fn already_downloaded(&self, name: &str, len: Option<u64>) -> bool {
+        let Some(len) = len else {
+            return false;
+        };
+        fs::metadata(Path::new(&self.download_dir).join(name))
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() == len)
+    }
+
+    /// Cleanup files in the download directory
+    pub fn cleanup_files(&self) {
+        self.reserved_names.lock().unwrap().clear();
+        let files = match fs::read_dir(&self.download_dir) {
+            Ok(files) => files,
+            // Nothing to clean up, save_to_disk recreates it when needed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                error!("Failed to read download directory: {e}");
+                return;
+            }
+        };
+        for file in files.flatten() {
+            let path = file.path();
+            if path.is_file() {
+                remove_downloaded_file(&path);
+            }
+        }
+    }
+
+    /// Remove the files created by a finished batch, leaving anything other
+    /// sessions are still writing.
+    fn cleanup_batch(&self, files: Vec<String>) {
+        self.reserved_names.lock().unwrap().clear();
+        for path in files {
+            remove_downloaded_file(Path::new(&path));
+        }
+    }
+
+    pub fn check_memory_availability(
+        &self,
+        batch_size: usize,
+        estimated_file_size_mb: f64,
+    ) -> bool {
+        let available_memory_mb = self.available_memory_mb().unwrap_or(0.0);
+        let required_memory_mb = batch_size as f64 * estimated_file_size_mb;
+        say!(self, "\nMemory Check:");
+        say!(self, "╔═══════ Memory Analysis ═══════╗");
+        say!(
+            self,
+            "║ Available Memory: {:>8.1} MB ║",
+            available_memory_mb
+        );
+        say!(self, "║ Required Memory: {:>9.1} MB ║", required_memory_mb);
+        say!(self, "║ Batch Size: {:>17} ║", batch_size);
+        say!(
+            self,
+            "║ Est. File Size: {:>10.1} MB ║",
+            estimated_file_size_mb
+        );
+        say!(self, "╚═══════════════════════════════╝\n");
+        available_memory_mb > required_memory_mb
+    }
+
+    /// Free space in MB on the filesystem holding the download directory.
+    /// `None` when it isn't among the mounted disks sysinfo knows about.
+    fn available_disk_mb(&self) -> Option<f64> {
+        let dir = fs::canonicalize(&self.download_dir).ok()?;
+        let disks = Disks::new_with_refreshed_list();
+        // The deepest mount point containing the directory is the one it's on
+        disks
+            .list()
+            .iter()
+            .filter(|disk| dir.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())
+            .map(|disk| disk.available_space() as f64 / 1024.0 / 1024.0)
+    }
+
+    /// Keep the shared `System` snapshot fresh from a background task, so
+    /// downloads only ever read it. The task stops once the downloader is gone.
+    fn spawn_sysinfo_refresher(&self) {
+        let system = Arc::downgrade(&self.system);
+        let period = Duration::from_millis(self.options.sysinfo_refresh_ms.max(1));
+        let pid = Pid::from_u32(std::process::id());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            loop {
+                ticker.tick().await;
+                let Some(system) = system.upgrade() else {
+                    break;
+                };
+                let mut system = system.write().unwrap();
+                system.refresh_memory();
+                system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+            }
+        });
+    }
+
+    /// Memory available to us in MB, capped by the cgroup limit when running
+    /// in a container. `None` when the reading is implausible, e.g. zero.
+    fn available_memory_mb(&self) -> Option<f64> {
+        let system = self.system.read().unwrap();
+        let total_memory_mb = (system.total_memory() as f64) / 1024.0 / 1024.0;
+        let mut available_memory_mb = (system.available_memory() as f64) / 1024.0 / 1024.0;
+        if let Some(cgroup_mb) = cgroup_available_memory_mb() {
+            available_memory_mb = available_memory_mb.min(cgroup_mb);
+        }
+        (available_memory_mb > 0.0 && available_memory_mb <= total_memory_mb)
+            .then_some(available_memory_mb)
+    }
+
+    /// Print cumulative stats every `period` until the downloader is gone.
+    fn spawn_reporter(&self, period: Duration) {
+        let stats = Arc::downgrade(&self.stats);
+        let counters = self.counters.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(stats) = stats.upgrade() else {
+                    break;
+                };
+                let start_time = stats.lock().await.start_time;
+                let elapsed = Utc::now().timestamp() as u64 - start_time.unwrap_or(0);
+                let total_files = counters.total_files();
+                let mut stdout = io::stdout().lock();
+                let written = writeln!(
+                    stdout,
+                    "\n[report] {} files, {} failed, {:.2} GB in {} seconds ({:.2} files/second)",
+                    total_files.to_formatted_string(&Locale::en),
+                    counters.failed_downloads().to_formatted_string(&Locale::en),
+                    counters.total_bytes() as f64 / 1024.0 / 1024.0 / 1024.0,
+                    elapsed,
+                    total_files as f64 / elapsed.max(1) as f64
+                )
+                .and_then(|()| stdout.flush());
+                // Nobody is reading the reports any more
+                if written.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Send bodies to `sink` instead of the one picked by `options.sink`.
+    pub fn with_sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sink = Box::new(sink);
+        self
+    }
+
+    /// Memory budget in MB for buffering downloads, 0 once memory mode is
+    /// turned off.
+    pub fn max_memory_mb(&self) -> u64 {
+        self.max_memory_mb.load(Ordering::Relaxed)
+    }
+
+    /// Resident memory of this process in MB, `None` if the process is
+    /// missing from the current snapshot.
+    fn get_memory_usage_mb(&self) -> Option<f64> {
+        let system = self.system.read().unwrap();
+        match system.process(Pid::from_u32(std::process::id())) {
+            Some(process) => Some((process.memory() as f64) / 1024.0 / 1024.0),
+            None => {
+                if !self.memory_warned.swap(true, Ordering::Relaxed) {
+                    warn!("Could not read process memory usage, saving to disk");
+                }
+                None
+            }
+        }
+    }
+
+    /// Fold the current memory usage into the peak in `counters`.
+    fn record_peak_memory(&self) {
+        if let Some(memory_usage_mb) = self.get_memory_usage_mb() {
+            self.counters.record_memory_mb(memory_usage_mb);
+        }
+    }
+
+    /// Size advertised by the server, `None` if it doesn't send a
+    /// Content-Length (e.g. chunked transfer encoding).
+    async fn get_file_size(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+    ) -> Result<Option<u64>, DownloadError> {
+        let response = self.authorize(client.head(url)).send().await?;
+        if !response.status().is_success() {
+            // Some CDNs answer HEAD with 405 but serve GET just fine
+            return Ok(self.probe_get_size(client, url).await);
+        }
+        let headers = response.headers();
+        let Some(content_length) = headers.get("Content-Length") else {
+            // Typically a chunked response, whose size may still show up in
+            // the Content-Range of a one-byte range request
+            return Ok(self.probe_content_range(client, url).await);
+        };
+        content_length
+            .to_str()
+            .map_err(|e| DownloadError::ContentLength(e.to_string()))?
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|e| DownloadError::ContentLength(e.to_string()))
+    }
+
+    /// The size of `url` from GET requests, for servers that reject HEAD: the
+    /// `Content-Range` of a one-byte range, else the `Content-Length` of a full
+    /// response whose body is never read.
+    async fn probe_get_size(&self, client: &reqwest::Client, url: &str) -> Option<u64> {
+        if let Some(len) = self.probe_content_range(client, url).await {
+            return Some(len);
+        }
+        let response = self.authorize(client.get(url)).send().await.ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        // Dropping the unread response closes the connection
+        response.content_length()
+    }
+
+    /// The complete length from the `Content-Range` of a `bytes=0-0` request,
+    /// `None` if the server ignores the range or doesn't know the length.
+    async fn probe_content_range(&self, client: &reqwest::Client, url: &str) -> Option<u64> {
+        let response = self
+            .authorize(client.get(url).header(header::RANGE, "bytes=0-0"))
+            .send()
+            .await
+            .ok()?;
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return None;
+        }
+        content_range(response.headers())?.1
+    }
+
+    /// The size of `url` if the server advertises `Accept-Ranges: bytes` for it.
+    async fn probe_ranges(&self, client: &reqwest::Client, url: &str) -> Option<u64> {
+        let response = self.authorize(client.head(url)).send().await.ok()?;
+        let headers = response.headers();
+        let accepts_bytes = headers
+            .get(header::ACCEPT_RANGES)?
+            .to_str()
+            .ok()?
+            .split(',')
+            .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"));
+        if !accepts_bytes {
+            return None;
+        }
+        // content_length() is the (empty) body size for a HEAD response
+        headers
+            .get(header::CONTENT_LENGTH)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|&len| len > 0)
+    }
+
+    /// Take the next request slot allowed by the server's rate limit, sleeping
+    /// until it comes up.
+    async fn wait_for_rate_limit(&self) {
+        let wait = {
+            let mut state = self.rate_limit.lock().await;
+            let now = Instant::now();
+            let slot = state.next_allowed.filter(|t| *t > now).unwrap_or(now);
+            state.next_allowed = Some(slot + state.spacing);
+            slot - now
+        };
+        if !wait.is_zero() {
+            let mut lock = self.stats.lock().await;
+            lock.rate_limit_pauses += 1;
+            lock.rate_limit_wait += wait;
+            drop(lock);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Re-plan request pacing from the rate-limit headers of a response.
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        let read =
+            |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.trim().parse().ok() };
+        let (Some(remaining), Some(reset)) = (
+            read(&self.options.rate_limit_remaining_header),
+            read(&self.options.rate_limit_reset_header),
+        ) else {
+            return;
+        };
+        // Large values are absolute Unix timestamps rather than a delay
+        let now = Utc::now().timestamp() as u64;
+        let reset_in = Duration::from_secs(if reset > 1_000_000_000 {
+            reset.saturating_sub(now)
+        } else {
+            reset
+        });
+
+        let mut state = self.rate_limit.lock().await;
+        if remaining == 0 {
+            let resume_at = Instant::now() + reset_in;
+            if state.next_allowed.is_none_or(|t| t < resume_at) {
+                state.next_allowed = Some(resume_at);
+            }
+        } else {
+            state.spacing = reset_in / u32::try_from(remaining).unwrap_or(u32::MAX);
+        }
+    }
+
+    /// Last-Modified reported by a HEAD request, if the server sends one.
+    async fn get_last_modified(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+    ) -> Option<DateTime<Utc>> {
+        let response = self.authorize(client.head(url)).send().await.ok()?;
+        let last_modified = response
+            .headers()
+            .get(header::LAST_MODIFIED)?
+            .to_str()
+            .ok()?;
+        DateTime::parse_from_rfc2822(last_modified)
+            .ok()
+            .map(|date| date.with_timezone(&Utc))
+    }
+
+    /// Account for `bytes` just read against --rate-limit, sleeping long enough
+    /// to keep the combined throughput of all downloads under it.
+    async fn throttle(&self, bytes: usize) {
+        let Some(limit) = self.options.bandwidth_limit else {
+            return;
+        };
+        let limit = limit as f64;
+        let wait = {
+            let mut bucket = self.bandwidth.lock().await;
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * limit;
+            // Allow bursts of up to a second's worth of data
+            bucket.tokens = (bucket.tokens + refill).min(limit) - bytes as f64;
+            bucket.refilled_at = now;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / limit)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Exponential backoff for retry number `attempt` (1-based), with the
+    /// upper half jittered so concurrent downloads don't retry in lockstep.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .options
+            .retry_base_ms
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_RETRY_DELAY_MS);
+        let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+        Duration::from_millis(backoff - backoff / 2 + jitter)
+    }
+
+    /// Stream `body` into `file_name` chunk by chunk, so at most one chunk
+    /// per download is held in memory. Returns the body size.
+    async fn save_to_disk(
+        &self,
+        body: Body<'_>,
+        file_name: &str,
+        append: bool,
+    ) -> Result<u64, DownloadError> {
+        let mut disk_wait = Duration::ZERO;
+        let result = self
+            .write_body(body, file_name, append, &mut disk_wait)
+            .await;
+        if self.write_permits.is_some() {
+            self.stats.lock().await.disk_wait += disk_wait;
+        }
+        result
+    }
+
+    /// A --max-concurrent-writes permit, if there is a limit, adding the time
+    /// spent waiting for it to `disk_wait`.
+    async fn write_permit(&self, disk_wait: &mut Duration) -> Option<SemaphorePermit<'_>> {
+        let permits = self.write_permits.as_ref()?;
+        let waiting_since = Instant::now();
+        // Never closed, so this always gets a permit
+        let permit = permits.acquire().await.ok();
+        *disk_wait += waiting_since.elapsed();
+        permit
+    }
+
+    /// `save_to_disk` without the bookkeeping. Write permits are only held
+    /// around disk work, so a download waiting on the network doesn't keep
+    /// others from writing.
+    async fn write_body(
+        &self,
+        mut body: Body<'_>,
+        file_name: &str,
+        append: bool,
+        disk_wait: &mut Duration,
+    ) -> Result<u64, DownloadError> {
+        let permit = self.write_permit(disk_wait).await;
+        let mut file_path = format!("{}/{}", self.download_dir, file_name);
+        let create = |path: String| async move {
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .await
+        };
+        track_batch_file(&file_path);
+        let file = match create(file_path.clone()).await {
+            // Something removed the download directory behind our back
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!(
+                    "Download directory {} disappeared, recreating it",
+                    self.download_dir
+                );
+                self.setup_download_dir()?;
+                create(file_path.clone()).await
+            }
+            result => result,
+        };
+        let file = match file {
+            Ok(file) => Some(file),
+            Err(e) => match self.options.on_write_error {
+                WriteErrorPolicy::Fail => return Err(e.into()),
+                WriteErrorPolicy::Discard => {
+                    warn!("Discarding {} after write error: {}", file_name, e);
+                    self.stats.lock().await.write_errors += 1;
+                    None
+                }
+                WriteErrorPolicy::SafeName => {
+                    let safe_name = format!("{}.dat", Uuid::new_v4());
+                    warn!(
+                        "Saving {} as {} after write error: {}",
+                        file_name, safe_name, e
+                    );
+                    file_path = format!("{}/{}", self.download_dir, safe_name);
+                    track_batch_file(&file_path);
+                    let file = File::create(&file_path).await?;
+                    self.stats.lock().await.write_errors += 1;
+                    Some(file)
+                }
+            },
+        };
+        drop(permit);
+
+        let mut writer = file.map(BufWriter::new);
+        let mut received = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            let Some(out) = writer.as_mut() else {
+                continue;
+            };
+            let _permit = self.write_permit(disk_wait).await;
+            if let Err(e) = out.write_all(&chunk).await {
+                // Once the file is gone, keep reading so the download
+                // still completes and is measured
+                if self.options.on_write_error != WriteErrorPolicy::Discard {
+                    return Err(disk_write_error(e, &file_path));
+                }
+                warn!("Discarding {} after write error: {}", file_name, e);
+                self.stats.lock().await.write_errors += 1;
+                writer = None;
+            }
+        }
+
+        if let Some(mut writer) = writer {
+            let _permit = self.write_permit(disk_wait).await;
+            writer
+                .flush()
+                .await
+                .map_err(|e| disk_write_error(e, &file_path))?;
+            match self.options.fsync {
+                FsyncPolicy::None => {}
+                FsyncPolicy::PerFile => writer.get_ref().sync_all().await?,
+                FsyncPolicy::Batch => self.unsynced_files.lock().await.push(file_path),
+            }
+        }
+        Ok(received)
+    }
+
+    /// Warn if the files downloaded by the batch that just finished don't
+    /// all have the same content, for `--verify-identical`.
+    async fn check_batch_hashes(&self) {
+        let hashes = std::mem::take(&mut *self.batch_hashes.lock().await);
+        // Different URLs are expected to differ, so only files of the same
+        // URL are compared
+        let mut files_by_url: HashMap<&str, HashMap<&str, Vec<&str>>> = HashMap::new();
+        for (url, file_name, hash) in &hashes {
+            files_by_url
+                .entry(url)
+                .or_default()
+                .entry(hash)
+                .or_default()
+                .push(file_name);
+        }
+
+        let mut lock = self.stats.lock().await;
+        let mut divergent = false;
+        for (url, files_by_hash) in files_by_url {
+            lock.content_hashes
+                .extend(files_by_hash.keys().map(|h| h.to_string()));
+            if files_by_hash.len() > 1 {
+                divergent = true;
+                warn!(
+                    "Batch returned {} different contents for {}:",
+                    files_by_hash.len(),
+                    redact_url(url)
+                );
+                for (hash, files) in files_by_hash {
+                    warn!("  {hash}: {}", files.join(", "));
+                }
+            }
+        }
+        if divergent {
+            lock.divergent_batches += 1;
+        }
+    }
+
+    /// fsync every file written since the last call, for `--fsync batch`.
+    async fn sync_batch(&self) {
+        let files = std::mem::take(&mut *self.unsynced_files.lock().await);
+        for file_path in files {
+            let result = match File::open(&file_path).await {
+                Ok(file) => file.sync_all().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!("Failed to sync {}: {}", file_path, e);
+            }
+        }
+    }
+
+    pub async fn download_file(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        file_name: impl Into<String>,
+        bar: ProgressBar,
+    ) -> Result<DownloadOutcome, DownloadError> {
+        let file_name = file_name.into();
+        let _permit = match &self.request_permits {
+            Some(permits) => permits.acquire().await.ok(),
+            None => None,
+        };
+        let started = Instant::now();
+
+        if let Some(since) = self.options.modified_since {
+            if self
+                .get_last_modified(client, url)
+                .await
+                .is_some_and(|modified| modified <= since)
+            {
+                self.stats.lock().await.skipped_unchanged += 1;
+                self.progress_file(&bar);
+                return Ok(DownloadOutcome::Skipped);
+            }
+        }
+
+        // Only a file started from this very URL is continued
+        let resume_total = self
+            .options
+            .resume
+            .then(|| self.resume_info(&file_name))
+            .flatten()
+            .filter(|info| info.url == url)
+            .map(|info| info.total);
+        let resume_from = match resume_total {
+            Some(_) => fs::metadata(format!("{}/{}", self.download_dir, file_name))
+                .map_or(0, |metadata| metadata.len()),
+            None => 0,
+        };
+        let total = match resume_total.flatten() {
+            Some(total) => Some(total),
+            None if resume_from > 0 => self.get_file_size(client, url).await.ok().flatten(),
+            None => None,
+        };
+        if resume_from > 0 && total == Some(resume_from) {
+            say!(self, "{file_name} is already complete, not resuming it");
+            self.progress_file(&bar);
+            return Ok(DownloadOutcome::Skipped);
+        }
+
+        if self.options.respect_rate_limit_headers {
+            self.wait_for_rate_limit().await;
+        }
+
+        let cached = match &self.options.cache_manifest {
+            Some(_) if resume_from == 0 => self.cache.lock().await.get(url).cloned(),
+            _ => None,
+        };
+        let request = || {
+            let mut request = self.authorize(client.get(url));
+            if let Some(cached) = &cached {
+                if let Some(etag) = &cached.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &cached.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            match resume_from {
+                0 => request,
+                offset => request.header(header::RANGE, format!("bytes={offset}-")),
+            }
+        };
+        let mut attempt = 0;
+        // When the request that got the response went out, so the time to
+        // first byte leaves out probes, rate-limit waits and retry backoff
+        let mut sent_at;
+        let response = loop {
+            sent_at = Instant::now();
+            let result = match request().send().await {
+                // A pooled keep-alive connection the server already closed fails
+                // like this; try once more without reusing the connection
+                Err(e) if is_stale_connection_error(&e) => {
+                    self.stats.lock().await.stale_connection_retries += 1;
+                    sent_at = Instant::now();
+                    request().header(header::CONNECTION, "close").send().await
+                }
+                result => result,
+            };
+            let (retry_reason, requested_delay, max_retries) = match &result {
+                Err(e) if is_retryable_error(e) => (e.to_string(), None, self.options.retries),
+                Ok(response) if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => (
+                    "status code 429".to_string(),
+                    retry_after(response.headers()),
+                    self.options.retries.max(MIN_RATE_LIMITED_RETRIES),
+                ),
+                Ok(response) if response.status().is_server_error() => (
+                    format!("status code {}", response.status().as_str()),
+                    None,
+                    self.options.retries,
+                ),
+                _ => break result,
+            };
+            if attempt >= max_retries {
+                break result;
+            }
+            attempt += 1;
+            let delay = requested_delay.unwrap_or_else(|| self.retry_delay(attempt));
+            debug!(
+                "Retrying {url} in {} ms (attempt {attempt}/{max_retries}): {retry_reason}",
+                delay.as_millis()
+            );
+            let mut lock = self.stats.lock().await;
+            lock.retries += 1;
+            if requested_delay.is_some() {
+                lock.rate_limit_pauses += 1;
+                lock.rate_limit_wait += delay;
+            }
+            drop(lock);
+            tokio::time::sleep(delay).await;
+        };
+        let response = match response {
+            Ok(resp) => resp,
+            Err(e) => {
+                let e = DownloadError::from(e);
+                error!("Failed to download {}: {}", url, e);
+                let mut lock = self.stats.lock().await;
+                if matches!(e, DownloadError::Redirect(_)) {
+                    lock.truncated_redirects += 1;
+                }
+                *lock.error_counts.entry(e.category()).or_default() += 1;
+                self.counters.add_failure();
+                return Err(e);
+            }
+        };
+        self.counters.add_status(response.status().as_u16());
+        if self.options.respect_rate_limit_headers {
+            self.update_rate_limit(response.headers()).await;
+        }
+        if self.options.alpn.is_some() {
+            let protocol = match response.version() {
+                reqwest::Version::HTTP_2 => "h2",
+                reqwest::Version::HTTP_11 => "http/1.1",
+                _ => "other",
+            };
+            let mut lock = self.stats.lock().await;
+            *lock.protocols.entry(protocol.to_string()).or_default() += 1;
+        }
+        if self.options.only_status {
+            // Dropping the unread response closes the connection
+            let status = response.status();
+            drop(response);
+            self.progress_file(&bar);
+            if !status.is_success() {
+                debug!("{url} answered {}", status.as_str());
+                self.counters.add_failure();
+                return Err(DownloadError::Status(status));
+            }
+            return Ok(DownloadOutcome::Downloaded(0));
+        }
+        if let Some(reason) = captive_portal_hint(url, &response) {
+            if !self.portal_warned.swap(true, Ordering::Relaxed) {
+                warn!("Possible captive portal / auth redirect for {url}: {reason}");
+            }
+        }
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED && cached.is_some() {
+            debug!("Not modified since the cached response");
+            self.stats.lock().await.not_modified += 1;
+            self.progress_file(&bar);
+            return Ok(DownloadOutcome::Skipped);
+        }
+        if response.status().is_redirection() {
+            // Only returned when redirects aren't followed at all
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("nowhere");
+            error!(
+                "Failed to download {url}: not following {} redirect to {location}",
+                response.status().as_str()
+            );
+            self.counters.add_failure();
+            self.stats.lock().await.truncated_redirects += 1;
+            return Err(DownloadError::Status(response.status()));
+        }
+        if !response.status().is_success() {
+            error!(
+                "Failed to download {url}, status code: {}",
+                response.status().as_str()
+            );
+            self.counters.add_failure();
+            return Err(DownloadError::Status(response.status()));
+        }
+
+        let status = response.status();
+        let validators = CacheEntry::from_headers(response.headers());
+
+        if self.options.measure_only_first_byte {
+            // Dropping the unread response closes the connection
+            let ttfb = sent_at.elapsed();
+            drop(response);
+            self.stats.lock().await.ttfb_samples.push(ttfb);
+            self.progress_file(&bar);
+            return Ok(DownloadOutcome::Downloaded(0));
+        }
+
+        // A body of unknown size grows the bar as it arrives instead
+        let content_length = response.content_length();
+        if self.options.progress == ProgressUnit::Bytes {
+            bar.inc_length(content_length.unwrap_or(0));
+        }
+
+        // Only a 206 continues the partial file, a 200 replaces it
+        let append = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        if append {
+            // The range has to pick up where the file ends, of a file that
+            // still has the length it had when it was started
+            let continues = content_range(response.headers()).is_some_and(|(start, length)| {
+                start == resume_from && resume_total.flatten().is_none_or(|t| length == Some(t))
+            });
+            if !continues {
+                drop(response);
+                let file_path = format!("{}/{}", self.download_dir, file_name);
+                error!("Failed to resume {url}: the server's copy no longer matches {file_path}");
+                self.remove_rejected(&file_name);
+                self.counters.add_failure();
+                return Err(DownloadError::Resume(file_path));
+            }
+            self.stats.lock().await.resumed_downloads += 1;
+        }
+        // Without a size the default sink writes to disk, which is where a
+        // replaced partial file belongs
+        let size_hint = response.content_length().filter(|_| resume_from == 0);
+
+        let file_name = match content_disposition_filename(response.headers()) {
+            Some(name) if self.options.use_server_filename && !append => {
+                // Only known once the headers are in, but the body is never read
+                if !self.options.force && self.already_downloaded(&name, response.content_length())
+                {
+                    drop(response);
+                    debug!("{name} is already downloaded");
+                    self.stats.lock().await.skipped_existing += 1;
+                    self.progress_file(&bar);
+                    return Ok(DownloadOutcome::Skipped);
+                }
+                self.reserve_file_name(&name)
+            }
+            // A partial file keeps the name it was resumed under
+            _ if resume_from == 0 => {
+                let extension = content_type_extension(response.headers())
+                    .unwrap_or(self.options.default_ext.as_deref().unwrap_or("dat"));
+                let stem = file_name
+                    .rsplit_once('.')
+                    .map_or(&*file_name, |(stem, _)| stem);
+                format!("{stem}.{extension}")
+            }
+            _ => file_name,
+        };
+        if append {
+            // Goes with the file it describes
+            track_batch_file(&format!(
+                "{}/{}{}",
+                self.download_dir, file_name, RESUME_SUFFIX
+            ));
+        } else if self.options.resume {
+            let info = ResumeInfo {
+                url: url.to_string(),
+                total: content_length,
+            };
+            self.write_resume_info(&file_name, &info);
+        }
+
+        let expected_sha256 = self.options.expected_sha256(url);
+        let mut hasher =
+            (self.options.verify_identical || expected_sha256.is_some()).then(Sha256::new);
+        if let (true, Some(hasher)) = (append, hasher.as_mut()) {
+            // The digest covers the part downloaded by the earlier run too
+            let file_path = format!("{}/{}", self.download_dir, file_name);
+            if let Err(e) = hash_file(&file_path, hasher).await {
+                error!("Failed to read {}: {}", file_path, e);
+                self.counters.add_failure();
+                return Err(e.into());
+            }
+        }
+        let mut received = 0;
+        let body = response
+            .bytes_stream()
+            .then(|chunk| async move {
+                if let Ok(chunk) = &chunk {
+                    self.throttle(chunk.len()).await;
+                }
+                chunk
+            })
+            .inspect(|chunk| {
+                if let Ok(chunk) = chunk {
+                    received += chunk.len() as u64;
+                    if content_length.is_none() && self.options.progress == ProgressUnit::Bytes {
+                        bar.inc_length(chunk.len() as u64);
+                    }
+                    self.progress_bytes(&bar, chunk.len() as u64);
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(chunk);
+                    }
+                }
+            })
+            .boxed();
+        let written = self
+            .sink
+            .write(self, &file_name, append, size_hint, body)
+            .await;
+        self.counters.add_wire_bytes(received);
+        let content_len = match written {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Failed to save {} from {}: {}", file_name, url, e);
+                self.counters.add_failure();
+                return Err(e);
+            }
+        };
+
+        if let Some(hasher) = hasher {
+            let hash = hex::encode(hasher.finalize());
+            if let Some(expected) = expected_sha256.filter(|expected| *expected != hash) {
+                error!("Checksum mismatch for {url}: expected {expected}, got {hash}");
+                self.remove_rejected(&file_name);
+                self.counters.add_failure();
+                self.stats.lock().await.checksum_mismatches += 1;
+                return Err(DownloadError::Checksum {
+                    expected: expected.to_string(),
+                    got: hash,
+                });
+            }
+            if self.options.verify_identical {
+                self.batch_hashes
+                    .lock()
+                    .await
+                    .push((url.to_string(), file_name.clone(), hash));
+            }
+        }
+        if content_len < self.options.min_bytes {
+            error!(
+                "Rejected {url}: got {content_len} bytes, expected at least {}",
+                self.options.min_bytes
+            );
+            self.remove_rejected(&file_name);
+            self.counters.add_failure();
+            self.stats.lock().await.undersized_files += 1;
+            return Err(DownloadError::TooSmall {
+                got: content_len,
+                min: self.options.min_bytes,
+            });
+        }
+        self.counters.add_bytes(content_len);
+        if self.options.cache_manifest.is_some()
+            && (validators.etag.is_some() || validators.last_modified.is_some())
+        {
+            self.cache.lock().await.insert(url.to_string(), validators);
+        }
+
+        debug!(
+            status = status.as_u16(),
+            bytes = content_len,
+            duration_ms = started.elapsed().as_millis() as u64,
+            "Downloaded {file_name}"
+        );
+        if let Some(top_n) = self.options.top_n {
+            let timing = FileTiming {
+                url: url.to_string(),
+                bytes: content_len,
+                duration: started.elapsed(),
+            };
+            self.stats.lock().await.record_timing(timing, top_n);
+        }
+
+        self.progress_file(&bar);
+
+        Ok(DownloadOutcome::Downloaded(content_len))
+    }
+
+    /// Download `url` as `parts` byte ranges fetched concurrently, each written
+    /// at its offset in `file_name`. `content_length` comes from the range probe
+    /// and every range must match its share of it. Only --sha256 is checked,
+    /// the other response checks of `download_file` don't apply.
+    pub async fn download_ranges(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        file_name: impl Into<String>,
+        content_length: u64,
+        parts: u64,
+        bar: ProgressBar,
+    ) -> Result<DownloadOutcome, DownloadError> {
+        let file_name = file_name.into();
+        let started = Instant::now();
+        let file_path = format!("{}/{}", self.download_dir, file_name);
+
+        if self.options.respect_rate_limit_headers {
+            self.wait_for_rate_limit().await;
+        }
+
+        if self.options.progress == ProgressUnit::Bytes {
+            bar.inc_length(content_length);
+        }
+        let result = self
+            .fetch_ranges(client, url, &file_path, content_length, parts, &bar)
+            .await;
+        let received = match &result {
+            Ok(received) | Err((received, _)) => *received,
+        };
+        self.counters.add_wire_bytes(received);
+        if let Err((_, e)) = result {
+            error!("Failed to download {url} in {parts} ranges: {e}");
+            self.counters.add_failure();
+            return Err(e);
+        }
+
+        // Ranges arrive out of order, so hash the assembled file
+        if let Some(expected) = self.options.expected_sha256(url) {
+            let mut hasher = Sha256::new();
+            let hash = hash_file(&file_path, &mut hasher)
+                .await
+                .map(|()| hex::encode(hasher.finalize()));
+            if hash.as_deref().ok() != Some(expected) {
+                self.remove_rejected(&file_name);
+                self.counters.add_failure();
+                self.stats.lock().await.checksum_mismatches += 1;
+                return Err(match hash {
+                    Ok(hash) => {
+                        error!("Checksum mismatch for {url}: expected {expected}, got {hash}");
+                        DownloadError::Checksum {
+                            expected: expected.to_string(),
+                            got: hash,
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to read {}: {}", file_path, e);
+                        e.into()
+                    }
+                });
+            }
+        }
+
+        self.counters.add_bytes(content_length);
+        debug!(
+            bytes = content_length,
+            duration_ms = started.elapsed().as_millis() as u64,
+            "Downloaded {file_name} in {parts} ranges"
+        );
+        let mut lock = self.stats.lock().await;
+        if let Some(top_n) = self.options.top_n {
+            let timing = FileTiming {
+                url: url.to_string(),
+                bytes: content_length,
+                duration: started.elapsed(),
+            };
+            lock.record_timing(timing, top_n);
+        }
+        drop(lock);
+
+        self.progress_file(&bar);
+        Ok(DownloadOutcome::Downloaded(content_length))
+    }
+
+    /// Fetch the ranges for `download_ranges`, returning the body bytes
+    /// received alongside the result either way.
+    async fn fetch_ranges(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        file_path: &str,
+        content_length: u64,
+        parts: u64,
+        bar: &ProgressBar,
+    ) -> Result<u64, (u64, DownloadError)> {
+        let _permit = match &self.write_permits {
+            Some(permits) => permits.acquire().await.ok(),
+            None => None,
+        };
+        track_batch_file(file_path);
+        let file = File::create(file_path).await.map_err(|e| (0, e.into()))?;
+        file.set_len(content_length)
+            .await
+            .map_err(|e| (0, e.into()))?;
+
+        let received = AtomicU64::new(0);
+        let ranges = (0..parts).map(|i| {
+            let start = content_length * i / parts;
+            let end = content_length * (i + 1) / parts - 1;
+            self.fetch_range(client, url, file_path, start..=end, &received, bar)
+        });
+        let results = futures::future::join_all(ranges).await;
+        let received = received.load(Ordering::Relaxed);
+        // Every range checks its own length, so together they fill the file
+        for result in results {
+            result.map_err(|e| (received, e))?;
+        }
+
+        match self.options.fsync {
+            FsyncPolicy::None => {}
+            FsyncPolicy::PerFile => file.sync_all().await.map_err(|e| (received, e.into()))?,
+            FsyncPolicy::Batch => self.unsynced_files.lock().await.push(file_path.to_string()),
+        }
+        Ok(received)
+    }
+
+    /// Write bytes `range` of `url` at the same offset in `file_path`.
+    async fn fetch_range(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        file_path: &str,
+        range: RangeInclusive<u64>,
+        received: &AtomicU64,
+        bar: &ProgressBar,
+    ) -> Result<(), DownloadError> {
+        let (start, end) = range.into_inner();
+        let _permit = match &self.request_permits {
+            Some(permits) => permits.acquire().await.ok(),
+            None => None,
+        };
+        let response = self
+            .authorize(client.get(url))
+            .header(header::RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .await?;
+        self.counters.add_status(response.status().as_u16());
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(DownloadError::Range(format!(
+                "range {start}-{end} answered with status {}",
+                response.status().as_str()
+            )));
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(file_path)
+            .await?;
+        file.seek(io::SeekFrom::Start(start)).await?;
+        let mut writer = BufWriter::new(file);
+        let mut written = 0;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            self.throttle(chunk.len()).await;
+            received.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            self.progress_bytes(bar, chunk.len() as u64);
+            written += chunk.len() as u64;
+            // A server sending past the range would overwrite the next one
+            if written > end - start + 1 {
+                return Err(DownloadError::Range(format!(
+                    "range {start}-{end} returned too many bytes"
+                )));
+            }
+            writer
+                .write_all(&chunk)
+                .await
+                .map_err(|e| disk_write_error(e, file_path))?;
+        }
+        writer
+            .flush()
+            .await
+            .map_err(|e| disk_write_error(e, file_path))?;
+        if written != end - start + 1 {
+            return Err(DownloadError::Range(format!(
+                "range {start}-{end} returned {written} bytes"
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn stats_report(&self) -> StatsReport {
+        let lock = self.stats.lock().await;
+        let finished_at = Utc::now().timestamp() as u64;
+        let elapsed_secs = finished_at - lock.start_time.unwrap_or(finished_at);
+        // Sessions shorter than a second count as one
+        let rate_secs = elapsed_secs.max(1) as f64;
+        StatsReport {
+            schema_version: STATS_SCHEMA_VERSION,
+            version: VERSION,
+            run_name: self.options.run_name.clone(),
+            outcome: if self.aborted() {
+                "aborted"
+            } else if self.counters.failed_downloads() == 0 {
+                "success"
+            } else {
+                "failures"
+            },
+            stop_reason: lock.stop_reason.clone(),
+            started_at: lock.start_time,
+            finished_at,
+            elapsed_secs,
+            total_files: self.counters.total_files(),
+            failed_downloads: self.counters.failed_downloads(),
+            total_bytes: self.counters.total_bytes(),
+            files_per_sec: self.counters.total_files() as f64 / rate_secs,
+            bytes_per_sec: self.counters.total_bytes() as f64 / rate_secs,
+            peak_memory_mb: self.counters.peak_memory_mb(),
+        }
+    }
+
+    pub async fn display_completion_banner(&self) {
+        let lock = self.stats.lock().await;
+        let gb_downloaded = self.counters.total_bytes() as f64 / (1024.0 * 1024.0 * 1024.0);
+        let total_time = Utc::now().timestamp() as u64 - lock.start_time.unwrap_or(0);
+        let peak_memory = self
+            .counters
+            .peak_memory_mb()
+            .map_or_else(|| "unknown".to_string(), |mb| format!("{mb:.2} MB"));
+        // Sessions shorter than a second count as one
+        let throughput = format!(
+            "{:.2} files/s, {:.2} MB/s",
+            self.counters.total_files() as f64 / total_time.max(1) as f64,
+            self.counters.total_bytes() as f64 / 1024.0 / 1024.0 / total_time.max(1) as f64
+        );
+        if is_narrow_terminal() {
+            let mut summary = format!(
+                "Download {}\n  Total files: {}\n  Failed downloads: {}\n  \
+                 Data downloaded: {gb_downloaded:.2} GB\n  Throughput: {throughput}\n  \
+                 Peak memory: {peak_memory}\n  Total time: {total_time} seconds",
+                if self.aborted() {
+                    "aborted"
+                } else {
+                    "complete"
+                },
+                self.counters.total_files().to_formatted_string(&Locale::en),
+                self.counters
+                    .failed_downloads()
+                    .to_formatted_string(&Locale::en),
+            );
+            if let Some(statuses) = status_summary(
+                &self.counters.status_counts(),
+                &lock.error_counts,
+                usize::MAX,
+            ) {
+                summary.push_str(&format!("\n  Status codes: {statuses}"));
+            }
+            if let Some(reason) = &lock.stop_reason {
+                summary.push_str(&format!("\n  Stopped by: {reason}"));
+            }
+            let summary = if self.aborted() {
+                summary.red()
+            } else {
+                summary.green()
+            };
+            say!(self, "{}", summary);
+            return;
+        }
+        let (time_branch, stop_row) = match &lock.stop_reason {
+            Some(reason) => ("├", format!("║  └─ Stopped by: {reason:<34}    ║\n")),
+            None => ("└", String::new()),
+        };
+        let status_row =
+            match status_summary(&self.counters.status_counts(), &lock.error_counts, 34) {
+                Some(statuses) => format!("║  ├─ Status Codes: {statuses:<34}  ║\n"),
+                None => String::new(),
+            };
+        let completion_banner = format!(
+            "╔══════════════════ Download Complete ══════════════════╗
+║                                                       ║
+║  📊 Statistics:                                       ║
+║  ├─ Total Files: {:<35}  ║
+║  ├─ Failed Downloads: {:<30}  ║
+{}║  ├─ Data Downloaded: {:<30}   ║
+║  ├─ Throughput: {:<30}        ║
+║  ├─ Peak Memory: {:<30}       ║
+║  {}─ Total Time: {:<30}        ║
+{}║                                                       ║
+║  {}║
+║                                                       ║
+╚═══════════════════════════════════════════════════════╝",
+            self.counters.total_files().to_formatted_string(&Locale::en),
+            self.counters
+                .failed_downloads()
+                .to_formatted_string(&Locale::en),
+            status_row,
+            format!("{:.2} GB", gb_downloaded),
+            throughput,
+            peak_memory,
+            time_branch,
+            format!("{:.2} seconds", total_time),
+            stop_row,
+            if self.aborted() {
+                "❌ Download Session Aborted ❌                       "
+            } else {
+                "🎉 Download Session Completed Successfully! 🎉       "
+            }
+        );
+        let completion_banner = if self.aborted() {
+            completion_banner.red()
+        } else {
+            completion_banner.green()
+        };
+        say!(self, "{}", completion_banner);
+    }
+
+    pub async fn display_ttfb_percentiles(&self) {
+        let mut lock = self.stats.lock().await;
+        let samples = &mut lock.ttfb_samples;
+        if samples.is_empty() {
+            return;
+        }
+        samples.sort_unstable();
+        say!(self, "\nTime to first byte ({} requests):", samples.len());
+        for (label, p) in [
+            ("min", 0.0),
+            ("p50", 50.0),
+            ("p90", 90.0),
+            ("p99", 99.0),
+            ("max", 100.0),
+        ] {
+            let ms = percentile(samples, p).as_secs_f64() * 1000.0;
+            say!(self, "  {label:<4} {ms:>10.2} ms");
+        }
+    }
+
+    pub async fn display_status_distribution(&self) {
+        let lock = self.stats.lock().await;
+        let status_counts = self.counters.status_counts();
+        if status_counts.is_empty() && lock.error_counts.is_empty() {
+            return;
+        }
+        say!(self, "\nStatus distribution:");
+        let mut statuses: Vec<_> = status_counts.iter().collect();
+        statuses.sort();
+        for (status, count) in statuses {
+            say!(
+                self,
+                "  {status:<10} {}",
+                count.to_formatted_string(&Locale::en)
+            );
+        }
+        let mut errors: Vec<_> = lock.error_counts.iter().collect();
+        errors.sort();
+        for (category, count) in errors {
+            say!(
+                self,
+                "  {category:<10} {}",
+                count.to_formatted_string(&Locale::en)
+            );
+        }
+    }
+
+    pub async fn display_rate_histogram(&self) {
+        const BUCKETS: usize = 10;
+        const BAR_WIDTH: usize = 40;
+
+        let lock = self.stats.lock().await;
+        let rates = &lock.batch_rates;
+        if rates.is_empty() {
+            return;
+        }
+        let min = rates.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = rates.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let buckets = if max > min { BUCKETS } else { 1 };
+        let bucket_width = (max - min) / buckets as f64;
+
+        let mut counts = vec![0usize; buckets];
+        for rate in rates {
+            let i = if bucket_width > 0.0 {
+                ((rate - min) / bucket_width) as usize
+            } else {
+                0
+            };
+            counts[i.min(buckets - 1)] += 1;
+        }
+
+        let most = counts.iter().copied().max().unwrap_or(1);
+        say!(self, "\nBatch rate distribution ({} batches):", rates.len());
+        for (i, count) in counts.iter().enumerate() {
+            let low = min + bucket_width * i as f64;
+            let high = low + bucket_width;
+            let bar = "█".repeat(count * BAR_WIDTH / most);
+            say!(
+                self,
+                "  {low:>8.2} - {high:>8.2} files/s │{bar:<BAR_WIDTH$}│ {count}"
+            );
+        }
+    }
+
+    pub async fn display_protocols(&self) {
+        let lock = self.stats.lock().await;
+        if lock.protocols.is_empty() {
+            return;
+        }
+        say!(self, "\nNegotiated protocols:");
+        let mut protocols: Vec<_> = lock.protocols.iter().collect();
+        protocols.sort();
+        for (protocol, count) in protocols {
+            say!(
+                self,
+                "  {protocol:<10} {}",
+                count.to_formatted_string(&Locale::en)
+            );
+        }
+    }
+
+    pub async fn display_url_breakdown(&self) {
+        let lock = self.stats.lock().await;
+        if lock.url_counts.is_empty() {
+            return;
+        }
+        let elapsed = (Utc::now().timestamp() as u64 - lock.start_time.unwrap_or(0)).max(1);
+        say!(self, "\nPer-URL results:");
+        let mut urls: Vec<_> = lock.url_counts.iter().collect();
+        urls.sort_by(|a, b| a.0.cmp(b.0));
+        for (url, counts) in urls {
+            say!(
+                self,
+                "  {:>10} ok {:>8} failed {:>8.2} files/s  {url}",
+                counts.downloaded.to_formatted_string(&Locale::en),
+                counts.failed.to_formatted_string(&Locale::en),
+                counts.downloaded as f64 / elapsed as f64
+            );
+        }
+    }
+
+    pub async fn display_file_timings(&self) {
+        let lock = self.stats.lock().await;
+        for (title, timings) in [("Slowest", &lock.slowest), ("Fastest", &lock.fastest)] {
+            if timings.is_empty() {
+                continue;
+            }
+            say!(self, "\n{title} downloads:");
+            for (i, t) in timings.iter().enumerate() {
+                say!(
+                    self,
+                    "{:>3}. {:>9.3}s {:>10.2} MB  {}",
+                    i + 1,
+                    t.duration.as_secs_f64(),
+                    t.bytes as f64 / 1024.0 / 1024.0,
+                    t.url
+                );
+            }
+        }
+    }
+
+    /// Add the credentials from `--basic-auth` or `--bearer-token`.
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some((user, pass)) = &self.options.basic_auth {
+            request.basic_auth(user, Some(pass))
+        } else if let Some(token) = &self.options.bearer_token {
+            request.bearer_auth(token)
+        } else {
+            request
+        }
+    }
+
+    /// Client settings shared by the size probe and the download client.
+    fn client_builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::ClientBuilder::new()
+            .default_headers(self.default_headers.clone())
+            .user_agent(self.options.user_agent());
+        match self.options.max_redirects {
+            Some(0) => builder = builder.redirect(reqwest::redirect::Policy::none()),
+            Some(max) => builder = builder.redirect(reqwest::redirect::Policy::limited(max)),
+            None => {}
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(min_tls) = self.options.min_tls {
+            builder = builder.min_tls_version(min_tls.to_reqwest());
+        }
+        if let Some(max_tls) = self.options.max_tls {
+            builder = builder.max_tls_version(max_tls.to_reqwest());
+        }
+        builder
+    }
+
+    /// TLS versions allowed by `--min-tls` and `--max-tls`.
+    fn tls_versions(&self) -> Vec<&'static rustls::SupportedProtocolVersion> {
+        [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|v| self.options.min_tls.is_none_or(|min| *v >= min))
+            .filter(|v| self.options.max_tls.is_none_or(|max| *v <= max))
+            .map(TlsVersion::to_rustls)
+            .collect()
+    }
+
+    /// Advance `bar` for a finished file, unless it counts bytes.
+    fn progress_file(&self, bar: &ProgressBar) {
+        if self.options.progress == ProgressUnit::Files {
+            bar.inc(1);
+        }
+    }
+
+    /// Advance `bar` for `len` body bytes received, if it counts bytes.
+    fn progress_bytes(&self, bar: &ProgressBar, len: u64) {
+        if self.options.progress == ProgressUnit::Bytes {
+            bar.inc(len);
+        }
+    }
+
+    /// A progress bar that stays hidden for the first `progress_delay_ms`
+    /// and only starts drawing if it hasn't finished by then.
+    fn delayed_progress_bar(&self, len: u64) -> ProgressBar {
+        if self.options.quiet {
+            return ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+        }
+        let delay = Duration::from_millis(self.options.progress_delay_ms);
+        if delay.is_zero() {
+            return ProgressBar::new(len);
+        }
+        let bar = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+        let delayed = bar.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if !delayed.is_finished() {
+                delayed.set_draw_target(ProgressDrawTarget::stderr());
+            }
+        });
+        bar
+    }
+
+    /// Download `url` in batches until [`Downloader::stop`], an abort, or the --batches or
+    /// --duration limit, returning the stats of the whole session. Several
+    /// tasks can run `start` on the same downloader and share its stats.
+    pub async fn start(
+        &self,
+        url: &str,
+        batch_size: Option<usize>,
+        expected_file_size_mb: Option<f64>,
+    ) -> Result<StatsReport, DownloadError> {
+        let batch_size = batch_size.unwrap_or(20).max(1);
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            error!("Invalid URL. Please provide a URL that starts with 'http://' or 'https://'.");
+            return Err(DownloadError::InvalidUrl(url.to_string()));
+        }
+
+        let client = self.client_builder().build()?;
+        let file_size_mb = match (
+            self.get_file_size(&client, url).await,
+            expected_file_size_mb,
+        ) {
+            (Ok(Some(file_size)), _) if file_size > 0 => Some(file_size as f64 / 1024.0 / 1024.0),
+            // No usable Content-Length, e.g. a chunked response
+            (Ok(_), expected) => expected,
+            (Err(e), Some(expected)) => {
+                warn!("Could not determine file size ({e}), planning with {expected:.1} MB");
+                Some(expected)
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        if !self.background_tasks_started.swap(true, Ordering::Relaxed) {
+            self.spawn_sysinfo_refresher();
+            // Reports are progress output, which --quiet leaves out
+            if let Some(secs) = self
+                .options
+                .report_every_secs
+                .filter(|_| !self.options.quiet)
+            {
+                self.spawn_reporter(Duration::from_secs(secs));
+            }
+        }
+
+        let mut actual_batch_size = match (file_size_mb, self.available_memory_mb()) {
+            (Some(file_size_mb), Some(available_memory_mb)) => {
+                let actual_batch_size = std::cmp::min(
+                    batch_size,
+                    safe_batch_size(available_memory_mb, file_size_mb),
+                );
+
+                say!(
+                    self,
+                    "\nAdjusted batch size to {actual_batch_size} based on available memory"
+                );
+
+                if !self.check_memory_availability(actual_batch_size, file_size_mb) {
+                    warn!("Running in disk-based mode with reduced batch size");
+                    let required_disk_mb = actual_batch_size as f64 * file_size_mb;
+                    match self.available_disk_mb() {
+                        Some(free_mb) if free_mb < required_disk_mb => warn!(
+                            "A batch needs about {required_disk_mb:.1} MB on disk \
+                             but only {free_mb:.1} MB is free in {}",
+                            self.download_dir
+                        ),
+                        Some(_) => {}
+                        None => warn!("Free space in {} can't be determined", self.download_dir),
+                    }
+                    self.max_memory_mb.store(0, Ordering::Relaxed);
+                }
+                actual_batch_size
+            }
+            (Some(_), None) => {
+                let fallback = self.options.fallback_batch_size.max(1) as usize;
+                let actual_batch_size = std::cmp::min(batch_size, fallback);
+                warn!(
+                    "Available memory can't be determined, \
+                     using fallback batch size {actual_batch_size}"
+                );
+                actual_batch_size
+            }
+            (None, _) => {
+                warn!(
+                    "File size unknown, keeping batch size {batch_size} \
+                     (pass --expected-file-size-mb to size batches by memory)"
+                );
+                batch_size
+            }
+        };
+
+        match self.options.max_concurrency {
+            Some(limit) if (limit as usize) < actual_batch_size => {
+                say!(
+                    self,
+                    "Effective concurrency: {limit} requests (capped by --max-concurrency)"
+                )
+            }
+            _ => say!(self, "Effective concurrency: {actual_batch_size} requests"),
+        }
+
+        if self.options.fsync != FsyncPolicy::None {
+            let policy = self.options.fsync.to_possible_value().unwrap();
+            say!(self, "Durability: fsync {}", policy.get_name());
+        }
+
+        let mut builder = self
+            .client_builder()
+            .pool_max_idle_per_host(actual_batch_size)
+            .timeout(Duration::from_secs(30));
+        if let Some(alpn) = &self.options.alpn {
+            say!(self, "Offering ALPN protocols: {}", alpn.join(", "));
+            let versions = self.tls_versions();
+            builder = builder.use_preconfigured_tls(tls_config_with_alpn(alpn, &versions)?);
+        }
+        let client = builder.build()?;
+
+        let mut lock = self.stats.lock().await;
+        if lock.start_time.is_none() {
+            lock.start_time = Some(Utc::now().timestamp() as u64);
+        }
+        drop(lock);
+
+        let ramp_up = Duration::from_secs(self.options.ramp_up_secs);
+        let ramp_started = Instant::now();
+        let mut ramped_up = ramp_up.is_zero() || actual_batch_size == 1;
+        if !ramped_up {
+            say!(
+                self,
+                "Ramping batch size from 1 to {actual_batch_size} over {} seconds",
+                ramp_up.as_secs()
+            );
+        }
+
+        let rotate = !self.options.url_list.is_empty();
+        let targets = if rotate {
+            self.options
+                .url_list
+                .iter()
+                .map(|url| WeightedUrl {
+                    url: url.clone(),
+                    weight: 1.0,
+                })
+                .collect()
+        } else if self.options.weighted_urls.is_empty() {
+            vec![WeightedUrl {
+                url: url.to_string(),
+                weight: 1.0,
+            }]
+        } else {
+            self.options.weighted_urls.clone()
+        };
+        // Only fails for empty or non-positive weights, which the parser rejects
+        let target_index = WeightedIndex::new(targets.iter().map(|t| t.weight))?;
+        let mut next_target = 0;
+        if rotate {
+            say!(self, "Rotating through {} URLs", targets.len());
+        } else if targets.len() > 1 {
+            let total: f64 = targets.iter().map(|t| t.weight).sum();
+            say!(self, "Distributing requests over {} URLs:", targets.len());
+            for target in &targets {
+                say!(
+                    self,
+                    "  {:>5.1}%  {}",
+                    target.weight / total * 100.0,
+                    target.url
+                );
+            }
+        }
+
+        let split_length = match self.options.split {
+            Some(_) if targets.len() > 1 => {
+                warn!("--split only applies to a single URL, ignoring it");
+                None
+            }
+            Some(parts) => match self.probe_ranges(&client, url).await {
+                Some(len) => {
+                    say!(self, "Downloading each file in {parts} ranges");
+                    Some(len)
+                }
+                None => {
+                    warn!("{url} doesn't accept byte ranges, downloading whole files");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut smoothed_rate: Option<f64> = None;
+        let mut below_min_rate_since: Option<Instant> = None;
+
+        let batch_interval = Duration::from_millis(
+            self.options
+                .batch_interval_ms
+                .unwrap_or(DEFAULT_BATCH_INTERVAL_MS),
+        );
+        let mut stopped = self.stop.subscribe();
+
+        loop {
+            tokio::select! {
+                // Checked first so a stop wins even when the interval is zero
+                biased;
+                _ = stopped_by(&mut stopped) => break,
+                _ = tokio::time::sleep(batch_interval) => {
+                    if self.aborted.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    if let Some(secs) = self.options.duration_secs {
+                        if ramp_started.elapsed() >= Duration::from_secs(secs) {
+                            self.stats
+                                .lock()
+                                .await
+                                .stop_reason
+                                .get_or_insert_with(|| format!("--duration {secs}"));
+                            break;
+                        }
+                    }
+                    if let Some(min_free_mb) = self.options.min_free_disk_mb {
+                        match self.available_disk_mb() {
+                            Some(free_mb) if free_mb < min_free_mb as f64 => {
+                                warn!(
+                                    "Only {free_mb:.1} MB free in {}, stopping",
+                                    self.download_dir
+                                );
+                                self.stats
+                                    .lock()
+                                    .await
+                                    .stop_reason
+                                    .get_or_insert_with(|| format!("--min-free-disk-mb {min_free_mb}"));
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                    let batch_number = self.batches_started.fetch_add(1, Ordering::Relaxed) + 1;
+                    if let Some(limit) = self.options.batches {
+                        if batch_number > limit {
+                            self.stats
+                                .lock()
+                                .await
+                                .stop_reason
+                                .get_or_insert_with(|| format!("--batches {limit}"));
+                            break;
+                        }
+                    }
+
+                    // Other processes may have taken or freed memory since the
+                    // last batch; never grow past the batch size asked for
+                    if let (Some(file_size_mb), Some(available_memory_mb)) =
+                        (file_size_mb, self.available_memory_mb())
+                    {
+                        let adjusted = std::cmp::min(
+                            batch_size,
+                            safe_batch_size(available_memory_mb, file_size_mb),
+                        );
+                        if adjusted != actual_batch_size {
+                            say!(self,
+                                "\nAdjusted batch size from {actual_batch_size} to {adjusted} \
+                                 ({available_memory_mb:.1} MB available)"
+                            );
+                            actual_batch_size = adjusted;
+                        }
+                    }
+
+                    let current_batch_size = if ramped_up {
+                        actual_batch_size
+                    } else {
+                        let elapsed = ramp_started.elapsed();
+                        let progress = (elapsed.as_secs_f64() / ramp_up.as_secs_f64()).min(1.0);
+                        let size = 1 + ((actual_batch_size - 1) as f64 * progress) as usize;
+                        if size == actual_batch_size {
+                            ramped_up = true;
+                            say!(self,
+                                "Reached full batch size of {actual_batch_size} after {:.1} seconds",
+                                elapsed.as_secs_f64()
+                            );
+                        }
+                        size
+                    };
+
+                    let batch_started = Instant::now();
+                    let batch_span = info_span!("batch", number = batch_number);
+                    let mut tasks = Vec::with_capacity(current_batch_size);
+                    // A byte bar grows as each response reports its size
+                    let (len, counter) = match self.options.progress {
+                        ProgressUnit::Files => {
+                            (current_batch_size as u64, "{pos}/{len} ({eta})")
+                        }
+                        ProgressUnit::Bytes => (0, "{bytes}/{total_bytes} ({bytes_per_sec}, {eta})"),
+                    };
+                    // Same switch as the banners: --no-color, NO_COLOR or no terminal
+                    let template = if colored::control::SHOULD_COLORIZE.should_colorize() {
+                        format!("{{spinner:.green}} [{{elapsed_precise}}] [{{wide_bar:.cyan/blue}}] {counter}")
+                    } else {
+                        format!("[{{elapsed_precise}}] [{{wide_bar}}] {counter}")
+                    };
+                    let bar = self.delayed_progress_bar(len);
+                    bar.set_style(
+                        ProgressStyle::default_bar()
+                            .template(&template)
+                            .unwrap()
+                            .progress_chars("#>-"),
+                    );
+
+                    bar.tick();
+
+                    let mut batch_urls = Vec::with_capacity(current_batch_size);
+                    for _ in 0..current_batch_size {
+                        let index = if rotate {
+                            let index = next_target;
+                            next_target = (next_target + 1) % targets.len();
+                            index
+                        } else {
+                            target_index.sample(&mut rand::thread_rng())
+                        };
+                        let target = &targets[index].url;
+                        let file_name = self
+                            .take_resumable(target)
+                            .await
+                            .unwrap_or_else(|| format!("{}.dat", Uuid::new_v4()));
+                        let d = match (split_length, self.options.split) {
+                            (Some(len), Some(parts)) => Either::Left(self.download_ranges(
+                                &client,
+                                target,
+                                file_name,
+                                len,
+                                parts,
+                                bar.clone(),
+                            )),
+                            _ => Either::Right(self.download_file(
+                                &client,
+                                target,
+                                file_name,
+                                bar.clone(),
+                            )),
+                        };
+                        tasks.push(
+                            async {
+                                let result = d.await;
+                                self.record_peak_memory();
+                                result
+                            }
+                            .instrument(debug_span!(parent: &batch_span, "download", url = %redact_url(target))),
+                        );
+                        batch_urls.push(target);
+                    }
+
+                    let mut batch_stopped = self.stop.subscribe();
+                    let (results, batch_files) = BATCH_FILES
+                        .scope(Default::default(), async {
+                            let results = tokio::select! {
+                                results = futures::future::join_all(tasks) => Some(results),
+                                _ = stopped_by(&mut batch_stopped) => None,
+                            };
+                            (results, BATCH_FILES.with(|files| files.take()))
+                        })
+                        .instrument(batch_span.clone())
+                        .await;
+                    // Dropping the downloads leaves partial files, which only
+                    // --resume and --keep want
+                    let Some(results) = results else {
+                        bar.finish_and_clear();
+                        if !self.options.resume && !self.options.keep {
+                            self.cleanup_batch(batch_files);
+                        }
+                        break;
+                    };
+
+                    if targets.len() > 1 {
+                        let mut lock = self.stats.lock().await;
+                        for (target, result) in batch_urls.iter().zip(&results) {
+                            let counts = lock.url_counts.entry(target.to_string()).or_default();
+                            match result {
+                                Ok(DownloadOutcome::Downloaded(_)) => counts.downloaded += 1,
+                                Ok(DownloadOutcome::Skipped) => {}
+                                Err(_) => counts.failed += 1,
+                            }
+                        }
+                    }
+
+                    let mut successful_downloads = 0;
+                    let mut batch_bytes = 0;
+                    for result in &results {
+                        if let Ok(DownloadOutcome::Downloaded(bytes)) = result {
+                            successful_downloads += 1;
+                            batch_bytes += bytes;
+                        }
+                    }
+                    self.counters.add_files(successful_downloads);
+
+                    bar.finish();
+
+                    // Only the batch itself, so the pause between batches
+                    // doesn't count against the rate
+                    let elapsed = batch_started.elapsed();
+                    let elapsed_time = elapsed.as_secs_f64().max(0.001);
+                    let (avg_speed, bytes_per_sec) =
+                        batch_rates(successful_downloads, batch_bytes, elapsed);
+
+                    say!(self,
+                        "\n{successful_downloads} of {current_batch_size} files downloaded in \
+                         {elapsed_time:.2} seconds"
+                    );
+                    let overall_bytes_per_sec = self.counters.total_bytes() as f64
+                        / ramp_started.elapsed().as_secs_f64().max(0.001);
+                    say!(self,
+                        "average speed: {avg_speed:.2} files/second, {:.2} MB/s \
+                         ({:.2} MB/s overall)",
+                        bytes_per_sec / 1024.0 / 1024.0,
+                        overall_bytes_per_sec / 1024.0 / 1024.0
+                    );
+
+                    info!(
+                        parent: &batch_span,
+                        size = current_batch_size,
+                        successful = successful_downloads,
+                        failed = current_batch_size - successful_downloads,
+                        bytes = batch_bytes,
+                        seconds = elapsed_time,
+                        "Batch finished"
+                    );
+
+                    if self.options.rate_histogram {
+                        self.stats.lock().await.batch_rates.push(avg_speed);
+                    }
+
+                    if let Some(path) = &self.options.failures_file {
+                        let timestamp = Utc::now().to_rfc3339();
+                        let lines: String = batch_urls
+                            .iter()
+                            .zip(&results)
+                            .filter_map(|(target, result)| {
+                                let e = result.as_ref().err()?;
+                                Some(format!("{target} # batch {batch_number}, {timestamp}, {e}\n"))
+                            })
+                            .collect();
+                        if !lines.is_empty() {
+                            let written = fs::OpenOptions::new()
+                                .create(true)
+                                .append(true)
+                                .open(path)
+                                .and_then(|mut file| file.write_all(lines.as_bytes()));
+                            if let Err(e) = written {
+                                error!("Failed to write {}: {}", path.display(), e);
+                            }
+                        }
+                    }
+
+                    if let Some(path) = &self.options.csv_log {
+                        let failed = results.iter().filter(|result| result.is_err()).count();
+                        let row = format!(
+                            "{},{},{current_batch_size},{successful_downloads},{failed},{batch_bytes},{avg_speed:.2}\n",
+                            Utc::now().to_rfc3339(),
+                            csv_field(&self.options.run_name)
+                        );
+                        if let Err(e) = self.append_csv_row(path, &row) {
+                            error!("Failed to write {}: {}", path.display(), e);
+                        }
+                    }
+
+                    self.save_cache_manifest().await;
+
+                    if let Some(min_rate) = self.options.min_rate {
+                        let rate = smoothed_rate.map_or(avg_speed, |previous| {
+                            RATE_SMOOTHING * avg_speed + (1.0 - RATE_SMOOTHING) * previous
+                        });
+                        smoothed_rate = Some(rate);
+                        if rate >= min_rate {
+                            below_min_rate_since = None;
+                        } else {
+                            let since = *below_min_rate_since.get_or_insert_with(Instant::now);
+                            if since.elapsed().as_secs() >= self.options.min_rate_window_secs {
+                                error!(
+                                    "Aborting: smoothed rate {rate:.2} files/second stayed below \
+                                     {min_rate:.2} for {} seconds",
+                                    since.elapsed().as_secs()
+                                );
+                                self.stats
+                                    .lock()
+                                    .await
+                                    .stop_reason
+                                    .get_or_insert_with(|| format!("--min-rate {min_rate}"));
+                                // Stops once this batch is synced and cleaned up
+                                self.aborted.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
+
+                    if self.options.verify_identical {
+                        self.check_batch_hashes().await;
+                    }
+                    self.sync_batch().await;
+                    if !self.options.keep {
+                        self.cleanup_batch(batch_files);
+                    }
+
+                    let mut lock = self.stats.lock().await;
+                    for result in &results {
+                        match result {
+                            Ok(_) => lock.consecutive_failures = 0,
+                            Err(_) => lock.consecutive_failures += 1,
+                        }
+                    }
+                    if let Some(limit) = self.options.max_consecutive_failures {
+                        if lock.consecutive_failures as u64 >= limit {
+                            error!(
+                                "Aborting: the last {} downloads all failed",
+                                lock.consecutive_failures
+                            );
+                            lock.stop_reason.get_or_insert_with(|| {
+                                format!("--max-consecutive-failures {limit}")
+                            });
+                            self.aborted.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    drop(lock);
+
+                    if self.aborted.load(Ordering::Relaxed) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(self.stats_report().await)
+    }
+}
+
+/// `value` as a CSV field, quoted when it holds a separator, quote or line
+/// break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Wait for [`Downloader::stop`]. The receiver's guard is dropped right away,
+/// so this can be held across other awaits.
+async fn stopped_by(stop: &mut watch::Receiver<bool>) {
+    stop.wait_for(|stopped| *stopped).await.ok();
+}
+
+/// Read the --cache-manifest left by an earlier run. A missing file starts an
+/// empty cache; an unreadable one is reported and ignored.
+fn load_cache_manifest(path: &Path) -> HashMap<String, CacheEntry> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            error!("Failed to read {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        warn!("Ignoring {}: {}", path.display(), e);
+        HashMap::new()
+    })
+}
+
+/// A file extension for the MIME type in a `Content-Type` header.
+fn content_type_extension(headers: &HeaderMap) -> Option<&'static str> {
+    let value = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let mime = value.split(';').next()?.trim().to_ascii_lowercase();
+    let extension = match mime.as_str() {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-7z-compressed" => "7z",
+        "application/x-xz" => "xz",
+        "application/json" => "json",
+        "application/xml" | "text/xml" => "xml",
+        "application/javascript" | "text/javascript" => "js",
+        "application/wasm" => "wasm",
+        "text/plain" => "txt",
+        "text/html" => "html",
+        "text/css" => "css",
+        "text/csv" => "csv",
+        "audio/mpeg" => "mp3",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        _ => return None,
+    };
+    Some(extension)
+}
+
+/// The file name from a `Content-Disposition` header, preferring the RFC 5987
+/// `filename*` form. Anything that could point outside the download directory
+/// is stripped: only the last path component is kept, and `.`/`..` are refused.
+fn content_disposition_filename(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::CONTENT_DISPOSITION)?.to_str().ok()?;
+    let mut plain = None;
+    let mut extended = None;
+    for param in value.split(';').skip(1) {
+        let Some((key, val)) = param.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_ascii_lowercase().as_str() {
+            "filename" => plain = Some(val.trim().trim_matches('"').to_string()),
+            "filename*" => {
+                // charset'language'percent-encoded-name
+                if let Some(encoded) = val.trim().splitn(3, '\'').nth(2) {
+                    extended = percent_decode(encoded);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let name = extended.or(plain)?;
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    match name.as_str() {
+        "" | "." | ".." => None,
+        _ => Some(name),
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Feed the contents of the file at `path` into `hasher`.
+async fn hash_file(path: &str, hasher: &mut Sha256) -> io::Result<()> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf).await? {
+            0 => return Ok(()),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// Whether a request failed on an established connection, as opposed to while
+/// connecting or by timing out. Under the batch model that almost always means
+/// a reused keep-alive connection that the server had closed in the meantime.
+fn is_stale_connection_error(e: &reqwest::Error) -> bool {
+    e.is_request() && !e.is_connect() && !e.is_timeout()
+}
+
+/// Whether a failed request is worth retrying: timeouts and connection
+/// failures, except TLS version mismatches that would fail the same way again.
+fn is_retryable_error(e: &reqwest::Error) -> bool {
+    (e.is_timeout() || e.is_connect() || is_stale_connection_error(e))
+        && !is_tls_version_mismatch(e)
+}
+
+/// How long a `Retry-After` header asks us to wait, given either in seconds or
+/// as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means we may retry right away
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Explain why `response` looks like it came from a captive portal or login
+/// page rather than from the server behind `requested`: the request was
+/// redirected to another host, or an HTML page came back for a URL naming a
+/// non-HTML file.
+fn captive_portal_hint(requested: &str, response: &reqwest::Response) -> Option<String> {
+    let requested = reqwest::Url::parse(requested).ok()?;
+    if requested.host_str() != response.url().host_str() {
+        return Some(format!("redirected to {}", response.url()));
+    }
+
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    let extension = requested
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension {
+        Some(ext) if is_html && ext != "html" && ext != "htm" => {
+            Some(format!("got an HTML page for a .{ext} file"))
+        }
+        _ => None,
+    }
+}
+
+/// Best-effort delivery of the session stats; a slow or broken endpoint must
+/// not hold up shutdown.
+pub async fn send_webhook(url: &str, report: &StatsReport) {
+    let client = match reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(5))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to send webhook: {e}");
+            return;
+        }
+    };
+    match client.post(url).json(report).send().await {
+        Ok(resp) if !resp.status().is_success() => {
+            warn!("Webhook returned status code: {}", resp.status().as_str());
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to send webhook: {e}"),
+    }
+}
+
+/// Memory left under the cgroup limit in MB, on Linux when a limit is set.
+#[cfg(target_os = "linux")]
+fn cgroup_available_memory_mb() -> Option<f64> {
+    let read = |path: &str| -> Option<u64> { fs::read_to_string(path).ok()?.trim().parse().ok() };
+    // cgroup v2 reports "max" when unlimited, which fails to parse
+    let (limit, usage) = read("/sys/fs/cgroup/memory.max")
+        .zip(read("/sys/fs/cgroup/memory.current"))
+        .or_else(|| {
+            read("/sys/fs/cgroup/memory/memory.limit_in_bytes")
+                .zip(read("/sys/fs/cgroup/memory/memory.usage_in_bytes"))
+        })?;
+    Some(limit.saturating_sub(usage) as f64 / 1024.0 / 1024.0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cgroup_available_memory_mb() -> Option<f64> {
+    None
+}
+
+/// Whether the TLS handshake failed because client and server have no
+/// protocol version in common.
+fn is_tls_version_mismatch(e: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        // io::Error hides the error it wraps from source()
+        let tls = err.downcast_ref::<rustls::Error>().or_else(|| {
+            err.downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref::<rustls::Error>())
+        });
+        if let Some(tls) = tls {
+            return matches!(
+                tls,
+                rustls::Error::AlertReceived(rustls::AlertDescription::ProtocolVersion)
+                    | rustls::Error::PeerIncompatible(_)
+            );
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// How many files of `file_size_mb` a batch may download at once with
+/// `available_memory_mb` to spare, at least one.
+fn safe_batch_size(available_memory_mb: f64, file_size_mb: f64) -> usize {
+    std::cmp::max(1, (available_memory_mb / file_size_mb * 2.0) as usize)
+}
+
+/// Files and bytes per second of a batch that took `elapsed`. Batches under
+/// a millisecond count as one, so an instant batch has a finite rate.
+fn batch_rates(files: usize, bytes: u64, elapsed: Duration) -> (f64, f64) {
+    let secs = elapsed.as_secs_f64().max(0.001);
+    (files as f64 / secs, bytes as f64 / secs)
+}
+
+/// Status codes and request failures (as `timeout`, `connect`, ...) with
+/// their counts, most frequent first, cut short with `…` to fit `width`.
+/// `None` before any request finished.
+fn status_summary(
+    status_counts: &HashMap<u16, usize>,
+    error_counts: &HashMap<&'static str, usize>,
+    width: usize,
+) -> Option<String> {
+    let mut entries: Vec<(String, usize)> = status_counts
+        .iter()
+        .map(|(status, count)| (status.to_string(), *count))
+        .chain(
+            error_counts
+                .iter()
+                .map(|(category, count)| (category.to_string(), *count)),
+        )
+        .collect();
+    entries.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    let mut summary = String::new();
+    for (i, (code, count)) in entries.iter().enumerate() {
+        let entry = format!("{code}: {}", count.to_formatted_string(&Locale::en));
+        let separator = if i == 0 { "" } else { ", " };
+        // Leave room for the `, …` of any entries that don't fit after this one
+        let reserved = if i + 1 < entries.len() { 3 } else { 0 };
+        if summary.chars().count() + separator.len() + entry.len() + reserved > width {
+            summary.push_str(separator);
+            summary.push('…');
+            break;
+        }
+        summary.push_str(separator);
+        summary.push_str(&entry);
+    }
+    (!summary.is_empty()).then_some(summary)
+}
+
+/// Nearest-rank percentile of an already sorted, non-empty slice.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Build a rustls config equivalent to reqwest's default one, but offering
+/// only `protocols` during ALPN negotiation and limited to `versions`.
+fn tls_config_with_alpn(
+    protocols: &[String],
+    versions: &[&'static rustls::SupportedProtocolVersion],
+) -> Result<rustls::ClientConfig, rustls::Error> {
+    let root_store = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.alpn_protocols = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(config)
+}
+
+/// Turn a failed write to `file_path` into an error that says so plainly
+/// when the disk is full, rather than leaving it to the OS message.
+fn disk_write_error(e: io::Error, file_path: &str) -> DownloadError {
+    if e.kind() == io::ErrorKind::StorageFull {
+        DownloadError::DiskFull(file_path.to_string())
+    } else {
+        e.into()
+    }
+}
+
+/// Delete a downloaded file. One that is already gone is fine, a download
+/// may have failed before creating it or another cleanup got there first.
+/// The first byte and complete length of a `Content-Range: bytes
+/// <first>-<last>/<length>` header, where the length may be `*`.
+fn content_range(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, length) = range.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let first = range.split_once('-')?.0.trim().parse().ok()?;
+    let length = match length.trim() {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+    Some((first, length))
+}
+
+fn remove_downloaded_file(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+    }
+}
+
+/// Whether stdout is a terminal too narrow for the boxed banners. Output that
+/// isn't going to a terminal keeps the full layout.
+pub fn is_narrow_terminal() -> bool {
+    terminal_size::terminal_size()
+        .is_some_and(|(terminal_size::Width(width), _)| width < WIDE_BANNER_COLUMNS)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::sink::{AutoSink, Body};
+
+    /// A quiet downloader writing into a fresh directory under the system
+    /// temp dir.
+    fn test_downloader(name: &str, options: DownloadOptions) -> (Downloader, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("auto-fast-dl-{name}-{}", Uuid::new_v4()));
+        let options = DownloadOptions {
+            quiet: true,
+            ..options
+        };
+        let downloader =
+            Downloader::new(Some(dir.to_string_lossy().into_owned()), None, options).unwrap();
+        (downloader, dir)
+    }
+
+    fn body(content: &'static [u8]) -> Body<'static> {
+        futures::stream::iter([Ok(Bytes::from_static(content))]).boxed()
+    }
+
+    /// Answer every request on a local port with `response`, verbatim, and
+    /// return the URL of a file on it.
+    async fn mock_server(response: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    let _ = socket.read(&mut request).await;
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{addr}/file.bin")
+    }
+
+    #[tokio::test]
+    async fn save_to_disk_recreates_a_removed_download_directory() {
+        let (downloader, dir) = test_downloader("removed-dir", DownloadOptions::default());
+        let written = downloader
+            .save_to_disk(body(b"first"), "a.dat", false)
+            .await;
+        assert_eq!(written.unwrap(), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+        let written = downloader
+            .save_to_disk(body(b"second"), "b.dat", false)
+            .await;
+        assert_eq!(written.unwrap(), 6);
+        assert_eq!(fs::read(dir.join("b.dat")).unwrap(), b"second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_header_or_proxy_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("auto-fast-dl-invalid-{}", Uuid::new_v4()));
+        let new =
+            |options| Downloader::new(Some(dir.to_string_lossy().into_owned()), None, options);
+        let options = DownloadOptions {
+            headers: vec![("X-Bad".to_string(), "line\nbreak".to_string())],
+            ..Default::default()
+        };
+        assert!(matches!(new(options), Err(DownloadError::InvalidHeader(_))));
+        let options = DownloadOptions {
+            proxy: Some("not a proxy".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(new(options), Err(DownloadError::InvalidUrl(_))));
+        // Rejected before the download directory is set up
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn counters_lose_no_updates_under_contention() {
+        const THREADS: usize = 8;
+        const UPDATES: usize = 10_000;
+        let counters = Counters::default();
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let counters = &counters;
+                scope.spawn(move || {
+                    for i in 0..UPDATES {
+                        counters.add_files(1);
+                        counters.add_failure();
+                        counters.add_bytes(3);
+                        counters.add_wire_bytes(5);
+                        counters.record_memory_mb((thread * UPDATES + i) as f64);
+                        counters.add_status(200);
+                        counters.add_status(418);
+                    }
+                });
+            }
+        });
+        let total = THREADS * UPDATES;
+        assert_eq!(counters.total_files(), total);
+        assert_eq!(counters.failed_downloads(), total);
+        assert_eq!(counters.total_bytes(), 3 * total as u64);
+        assert_eq!(counters.wire_bytes(), 5 * total as u64);
+        assert_eq!(counters.peak_memory_mb(), Some((total - 1) as f64));
+        assert_eq!(
+            counters.status_counts(),
+            HashMap::from([(200, total), (418, total)])
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_response_is_rejected_by_min_bytes() {
+        let url =
+            mock_server("HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+        let options = DownloadOptions {
+            min_bytes: 1,
+            keep: true,
+            sink: SinkKind::Disk,
+            ..Default::default()
+        };
+        let (downloader, dir) = test_downloader("empty", options);
+        let client = downloader.client_builder().build().unwrap();
+        let result = downloader
+            .download_file(&client, &url, "empty.dat", ProgressBar::hidden())
+            .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::TooSmall { got: 0, min: 1 })
+        ));
+        assert_eq!(downloader.counters.failed_downloads(), 1);
+        assert_eq!(downloader.stats.lock().await.undersized_files, 1);
+        // Even --keep doesn't keep a rejected file
+        assert!(!dir.join("empty.dat").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunked_response_without_length_is_downloaded() {
+        let url = mock_server(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await;
+        let options = DownloadOptions {
+            batches: Some(1),
+            batch_interval_ms: Some(0),
+            ..Default::default()
+        };
+        let (downloader, dir) = test_downloader("chunked", options);
+        let client = downloader.client_builder().build().unwrap();
+        assert_eq!(downloader.get_file_size(&client, &url).await.unwrap(), None);
+
+        // Planned with the expected size instead of the missing length
+        let report = downloader.start(&url, Some(2), Some(1.0)).await.unwrap();
+        assert_eq!(report.total_files, 2);
+        assert_eq!(report.failed_downloads, 0);
+        assert_eq!(report.total_bytes, 22);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_memory_reading_writes_to_disk() {
+        let (downloader, dir) = test_downloader("no-memory", DownloadOptions::default());
+        // A snapshot without any processes, as when this one wasn't found
+        *downloader.system.write().unwrap() = System::new();
+        assert_eq!(downloader.get_memory_usage_mb(), None);
+
+        let written = AutoSink
+            .write(&downloader, "small.dat", false, Some(5), body(b"small"))
+            .await;
+        assert_eq!(written.unwrap(), 5);
+        assert_eq!(fs::read(dir.join("small.dat")).unwrap(), b"small");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_rejects_a_range_of_a_different_length() {
+        let url = mock_server(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/20\r\n\
+             Content-Length: 5\r\nConnection: close\r\n\r\nworld",
+        )
+        .await;
+        let options = DownloadOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let (downloader, dir) = test_downloader("resume", options);
+        fs::write(dir.join("partial.dat"), b"hello").unwrap();
+        let info = ResumeInfo {
+            url: url.clone(),
+            total: Some(10),
+        };
+        downloader.write_resume_info("partial.dat", &info);
+
+        let client = downloader.client_builder().build().unwrap();
+        let result = downloader
+            .download_file(&client, &url, "partial.dat", ProgressBar::hidden())
+            .await;
+        assert!(matches!(result, Err(DownloadError::Resume(_))));
+        assert!(!dir.join("partial.dat").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_rates_divide_by_the_batch_duration() {
+        let (files_per_sec, bytes_per_sec) =
+            batch_rates(10, 25 * 1024 * 1024, Duration::from_millis(2500));
+        assert_eq!(files_per_sec, 4.0);
+        assert_eq!(bytes_per_sec, 10.0 * 1024.0 * 1024.0);
+
+        let (files_per_sec, _) = batch_rates(3, 0, Duration::ZERO);
+        assert_eq!(files_per_sec, 3000.0);
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{oneshot, Mutex, RwLock},
+    task::JoinHandle,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use auto_fast_dl::{DownloadOptions, Downloader};
+
+/// How long a job that ended can still be looked up before it's forgotten.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Deserialize)]
+struct JobRequest {
+    url: String,
+    batch_size: Option<usize>,
+    max_memory_mb: Option<u64>,
+    expected_file_size_mb: Option<f64>,
+    /// Finish after this many batches
+    batches: Option<u64>,
+    /// Finish after this many seconds
+    duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state", content = "error")]
+enum JobState {
+    Running,
+    Finished,
+    Cancelled,
+    Failed(String),
+}
+
+struct Job {
+    url: String,
+    downloader: Arc<Downloader>,
+    state: Mutex<JobState>,
+    cancelled: AtomicBool,
+}
+
+#[derive(Debug, Serialize)]
+struct JobStatus {
+    id: Uuid,
+    url: String,
+    #[serde(flatten)]
+    state: JobState,
+    total_files: usize,
+    failed_downloads: usize,
+    total_bytes: u64,
+}
+
+type Jobs = Arc<RwLock<HashMap<Uuid, Arc<Job>>>>;
+
+/// Run the job API on `addr` until Ctrl+C.
+///
+/// `POST /jobs` starts a download session in the background and returns its
+/// id, `GET /jobs/{id}` reports the session's stats and `DELETE /jobs/{id}`
+/// stops it. Every job downloads into its own subdirectory of `downloads`,
+/// removed when the job ends. Ended jobs can be looked up for
+/// [`FINISHED_JOB_TTL`].
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let jobs = Jobs::default();
+    let app = Router::new()
+        .route("/jobs", post(create_job))
+        .route("/jobs/{id}", get(job_status).delete(cancel_job))
+        .with_state(jobs);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+async fn create_job(
+    State(jobs): State<Jobs>,
+    Json(request): Json<JobRequest>,
+) -> (StatusCode, Json<serde_json::Value>) {
+    if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({ "error": "URL must start with 'http://' or 'https://'" })),
+        );
+    }
+
+    let id = Uuid::new_v4();
+    let download_dir = format!("downloads/{id}");
+    let downloader = match Downloader::new(
+        Some(download_dir.clone()),
+        request.max_memory_mb,
+        DownloadOptions {
+            run_name: id.to_string(),
+            batches: request.batches,
+            duration_secs: request.duration_secs,
+            // Banners and progress bars of every job would share the server's stdout
+            quiet: true,
+            ..Default::default()
+        },
+    ) {
+        Ok(downloader) => Arc::new(downloader),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+        }
+    };
+    let job = Arc::new(Job {
+        url: request.url,
+        downloader,
+        state: Mutex::new(JobState::Running),
+        cancelled: AtomicBool::new(false),
+    });
+    jobs.write().await.insert(id, job.clone());
+
+    tokio::spawn(async move {
+        let result = job
+            .downloader
+            .start(&job.url, request.batch_size, request.expected_file_size_mb)
+            .await;
+        *job.state.lock().await = match result {
+            Ok(_) if job.cancelled.load(Ordering::Relaxed) => JobState::Cancelled,
+            Ok(_) => JobState::Finished,
+            Err(e) => JobState::Failed(e.to_string()),
+        };
+        match tokio::fs::remove_dir_all(&download_dir).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {download_dir}: {e}"),
+        }
+
+        tokio::time::sleep(FINISHED_JOB_TTL).await;
+        jobs.write().await.remove(&id);
+    });
+
+    (StatusCode::ACCEPTED, Json(serde_json::json!({ "id": id })))
+}
+
+async fn job_status(
+    State(jobs): State<Jobs>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobStatus>, StatusCode> {
+    let job = jobs
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let state = job.state.lock().await.clone();
+    let counters = &job.downloader.counters;
+    Ok(Json(JobStatus {
+        id,
+        url: job.url.clone(),
+        state,
+        total_files: counters.total_files(),
+        failed_downloads: counters.failed_downloads(),
+        total_bytes: counters.total_bytes(),
+    }))
+}
+
+async fn cancel_job(State(jobs): State<Jobs>, Path(id): Path<Uuid>) -> StatusCode {
+    let Some(job) = jobs.read().await.get(&id).cloned() else {
+        return StatusCode::NOT_FOUND;
+    };
+    job.cancelled.store(true, Ordering::Relaxed);
+    job.downloader.stop();
+    StatusCode::ACCEPTED
+}
+
+/// The `/metrics` endpoint of a download run, see [`MetricsServer::start`].
+pub struct MetricsServer {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+/// What `/metrics` reads from, plus the byte count of the previous scrape to
+/// work out the current throughput.
+struct Metrics {
+    downloader: Arc<Downloader>,
+    last_scrape: Mutex<(Instant, u64)>,
+}
+
+impl MetricsServer {
+    /// Serve `downloader`'s counters in the Prometheus text format on
+    /// `port`, on all interfaces, until [`MetricsServer::shutdown`].
+    pub async fn start(port: u16, downloader: Arc<Downloader>) -> anyhow::Result<Self> {
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+        if !downloader.options.quiet {
+            println!(
+                "Serving metrics on http://{}/metrics",
+                listener.local_addr()?
+            );
+        }
+        let metrics = Arc::new(Metrics {
+            last_scrape: Mutex::new((Instant::now(), downloader.counters.total_bytes())),
+            downloader,
+        });
+        let app = Router::new()
+            .route("/metrics", get(metrics_text))
+            .with_state(metrics);
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    stopped.await.ok();
+                })
+                .await
+        });
+        Ok(MetricsServer { stop, task })
+    }
+
+    /// Stop accepting scrapes and wait for the ones in flight.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.stop.send(()).ok();
+        self.task.await??;
+        Ok(())
+    }
+}
+
+async fn metrics_text(State(metrics): State<Arc<Metrics>>) -> String {
+    let counters = &metrics.downloader.counters;
+    let total_bytes = counters.total_bytes();
+    // Bytes per second since the previous scrape, or since the server started
+    let throughput = {
+        let mut last_scrape = metrics.last_scrape.lock().await;
+        let (at, bytes) = *last_scrape;
+        *last_scrape = (Instant::now(), total_bytes);
+        total_bytes.saturating_sub(bytes) as f64 / at.elapsed().as_secs_f64().max(0.001)
+    };
+    let peak_memory_mb = counters.peak_memory_mb();
+
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        writeln!(
+            text,
+            "# HELP auto_fast_dl_{name} {help}\n\
+             # TYPE auto_fast_dl_{name} {kind}\n\
+             auto_fast_dl_{name} {value}"
+        )
+        .unwrap();
+    };
+    metric(
+        "files_total",
+        "counter",
+        "Files downloaded.",
+        counters.total_files() as f64,
+    );
+    metric(
+        "failed_downloads_total",
+        "counter",
+        "Downloads that failed.",
+        counters.failed_downloads() as f64,
+    );
+    metric(
+        "bytes_total",
+        "counter",
+        "Bytes of files downloaded.",
+        total_bytes as f64,
+    );
+    metric(
+        "throughput_bytes_per_second",
+        "gauge",
+        "Download rate since the previous scrape.",
+        throughput,
+    );
+    if let Some(mb) = peak_memory_mb {
+        metric(
+            "peak_memory_bytes",
+            "gauge",
+            "Highest process memory seen after a download finished.",
+            mb * 1024.0 * 1024.0,
+        );
+    }
+    text
+}
@@ -0,0 +1,148 @@
+//! Where downloaded bodies end up.
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
+use serde::Serialize;
+
+use crate::{error::DownloadError, Downloader};
+
+/// A response body, already throttled and hashed by the downloader.
+pub type Body<'a> = BoxStream<'a, reqwest::Result<Bytes>>;
+
+/// A destination for response bodies. `Downloader` hands every successful
+/// response to its sink and counts whatever size the sink reports.
+pub trait Sink: Send + Sync {
+    /// Consume `body` under `file_name`, returning its size. `append` is set
+    /// when the body continues a partial file left by an earlier run, and
+    /// `size_hint` is the Content-Length, if there was one.
+    fn write<'a>(
+        &'a self,
+        downloader: &'a Downloader,
+        file_name: &'a str,
+        append: bool,
+        size_hint: Option<u64>,
+        body: Body<'a>,
+    ) -> BoxFuture<'a, Result<u64, DownloadError>>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// Keep bodies that fit under --max-memory-mb in memory, write the rest
+    #[default]
+    Auto,
+    /// Write every body to the download directory
+    Disk,
+    /// Buffer every body in memory, whatever its size
+    Memory,
+    /// Drop every chunk as it arrives
+    Null,
+}
+
+impl SinkKind {
+    pub fn sink(self) -> Box<dyn Sink> {
+        match self {
+            SinkKind::Auto => Box::new(AutoSink),
+            SinkKind::Disk => Box::new(DiskSink),
+            SinkKind::Memory => Box::new(MemorySink),
+            SinkKind::Null => Box::new(NullSink),
+        }
+    }
+}
+
+/// Writes bodies into the download directory, honouring --fsync,
+/// --max-concurrent-writes and --on-write-error.
+pub struct DiskSink;
+
+impl Sink for DiskSink {
+    fn write<'a>(
+        &'a self,
+        downloader: &'a Downloader,
+        file_name: &'a str,
+        append: bool,
+        _size_hint: Option<u64>,
+        body: Body<'a>,
+    ) -> BoxFuture<'a, Result<u64, DownloadError>> {
+        Box::pin(downloader.save_to_disk(body, file_name, append))
+    }
+}
+
+/// Reads bodies into memory and lets them go once complete.
+pub struct MemorySink;
+
+impl Sink for MemorySink {
+    fn write<'a>(
+        &'a self,
+        _downloader: &'a Downloader,
+        _file_name: &'a str,
+        _append: bool,
+        size_hint: Option<u64>,
+        mut body: Body<'a>,
+    ) -> BoxFuture<'a, Result<u64, DownloadError>> {
+        Box::pin(async move {
+            // The hint is the server's Content-Length, which may be made up:
+            // when it can't be reserved the buffer just grows as chunks arrive
+            let mut content = Vec::new();
+            if let Some(hint) = size_hint {
+                let _ = content.try_reserve_exact(usize::try_from(hint).unwrap_or(usize::MAX));
+            }
+            while let Some(chunk) = body.next().await {
+                content.extend_from_slice(&chunk?);
+            }
+            Ok(content.len() as u64)
+        })
+    }
+}
+
+/// Drops every chunk as soon as it arrives, so only the transfer is measured.
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn write<'a>(
+        &'a self,
+        _downloader: &'a Downloader,
+        _file_name: &'a str,
+        _append: bool,
+        _size_hint: Option<u64>,
+        mut body: Body<'a>,
+    ) -> BoxFuture<'a, Result<u64, DownloadError>> {
+        Box::pin(async move {
+            let mut received = 0;
+            while let Some(chunk) = body.next().await {
+                received += chunk?.len() as u64;
+            }
+            Ok(received)
+        })
+    }
+}
+
+/// Buffers a body in memory when its Content-Length fits under the memory
+/// limit and writes it to disk otherwise. Decided before the body is read, so
+/// a file that doesn't fit is never buffered; without a size or a memory
+/// reading the body goes to disk.
+pub struct AutoSink;
+
+impl Sink for AutoSink {
+    fn write<'a>(
+        &'a self,
+        downloader: &'a Downloader,
+        file_name: &'a str,
+        append: bool,
+        size_hint: Option<u64>,
+        body: Body<'a>,
+    ) -> BoxFuture<'a, Result<u64, DownloadError>> {
+        let fits_in_memory = match (size_hint, downloader.get_memory_usage_mb()) {
+            _ if append => false,
+            (Some(len), Some(memory_usage_mb)) => {
+                memory_usage_mb + len as f64 / 1024.0 / 1024.0 < downloader.max_memory_mb() as f64
+            }
+            _ => false,
+        };
+        if fits_in_memory {
+            MemorySink.write(downloader, file_name, append, size_hint, body)
+        } else {
+            DiskSink.write(downloader, file_name, append, size_hint, body)
+        }
+    }
+}